@@ -0,0 +1,560 @@
+use crate::audio::Resampler;
+
+use bit_field::BitField;
+
+const DUTIES: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+const NOISE_DIVISORS: [usize; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// bits that read back as 1 in NR10-NR52, write-only and unused bits included
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf,
+    0xff, 0x3f, 0x00, 0xff, 0xbf,
+    0x7f, 0xff, 0x9f, 0xff, 0xbf,
+    0xff, 0xff, 0x00, 0x00, 0xbf,
+    0x00, 0x00, 0x70,
+];
+
+const NR10: usize = 0x00;
+const NR11: usize = 0x01;
+const NR12: usize = 0x02;
+const NR14: usize = 0x04;
+const NR21: usize = 0x06;
+const NR22: usize = 0x07;
+const NR24: usize = 0x09;
+const NR30: usize = 0x0a;
+const NR31: usize = 0x0b;
+const NR32: usize = 0x0c;
+const NR34: usize = 0x0e;
+const NR41: usize = 0x10;
+const NR42: usize = 0x11;
+const NR43: usize = 0x12;
+const NR44: usize = 0x13;
+const NR50: usize = 0x14;
+const NR51: usize = 0x15;
+const NR52: usize = 0x16;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Channel {
+    enabled: bool,
+    length: usize,
+    length_enabled: bool,
+    timer: usize,
+    // duty step of the square channels, sample index of the wave channel
+    position: usize,
+    volume: u8,
+    envelope_timer: u8,
+}
+
+/// Audio processing unit: two square channels, the wave channel and the noise channel.
+/// Registers 0xff10-0xff3f are read and written through it.
+#[derive(Debug)]
+pub struct APU {
+    pub cgb: bool,
+    pub power: bool,
+    pub wave_ram: [u8; 16],
+
+    regs: [u8; 0x17],
+    channels: [Channel; 4],
+    // the next step of the 512Hz frame sequencer, 0-7
+    frame_step: usize,
+
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    sweep_shadow: u16,
+    sweep_negated: bool,
+
+    wave_sample: u8,
+    // DMG: the CPU only sees wave RAM while the channel is playing in the cycle it reads it
+    wave_accessed: bool,
+    lfsr: u16,
+
+    resampler: Option<Resampler>,
+}
+
+impl APU {
+    pub fn new(cgb: bool) -> APU {
+        let mut apu = APU {
+            cgb,
+            power: true,
+            wave_ram: [0; 16],
+            regs: [0; 0x17],
+            channels: [Channel::default(); 4],
+            frame_step: 0,
+            sweep_enabled: false,
+            sweep_timer: 0,
+            sweep_shadow: 0,
+            sweep_negated: false,
+            wave_sample: 0,
+            wave_accessed: false,
+            lfsr: 0x7fff,
+            resampler: None,
+        };
+        // what the boot ROM leaves behind
+        apu.regs[NR11] = 0x80;
+        apu.regs[NR12] = 0xf3;
+        apu.regs[NR50] = 0x77;
+        apu.regs[NR51] = 0xf3;
+        apu
+    }
+
+    fn dac_enabled(&self, n: usize) -> bool {
+        match n {
+            2 => self.regs[NR30].get_bit(7),
+            _ => self.regs[n * 5 + 2] & 0xf8 != 0,
+        }
+    }
+
+    fn frequency(&self, n: usize) -> usize {
+        (self.regs[n * 5 + 3] as usize) | ((self.regs[n * 5 + 4] as usize & 0b111) << 8)
+    }
+
+    fn period(&self, n: usize) -> usize {
+        match n {
+            0 | 1 => (2048 - self.frequency(n)) * 4,
+            2 => (2048 - self.frequency(n)) * 2,
+            _ => {
+                let nr43 = self.regs[NR43];
+                NOISE_DIVISORS[(nr43 & 0b111) as usize] << (nr43 >> 4)
+            }
+        }
+    }
+
+    pub fn read(&self, i: u16) -> u8 {
+        let i = (i - 0xff10) as usize;
+        match i {
+            NR52 => {
+                let status = (0..4).fold(0, |s, n| s | (self.channels[n].enabled as u8) << n);
+                (self.power as u8) << 7 | READ_MASKS[NR52] | status
+            }
+            0..=0x16 => self.regs[i] | READ_MASKS[i],
+            0x17..=0x1f => 0xff,
+            _ => {
+                if !self.channels[2].enabled {
+                    self.wave_ram[i - 0x20]
+                } else if self.cgb || self.wave_accessed {
+                    self.wave_ram[self.channels[2].position / 2]
+                } else {
+                    0xff
+                }
+            }
+        }
+    }
+
+    /// PCM12 (0xff76) and PCM34 (0xff77) on CGB: the current digital output of two channels.
+    pub fn read_pcm(&self, i: u16) -> u8 {
+        let n = if i == 0xff76 { 0 } else { 2 };
+        self.digital(n + 1) << 4 | self.digital(n)
+    }
+
+    pub fn write(&mut self, i: u16, v: u8) {
+        let i = (i - 0xff10) as usize;
+        if i >= 0x20 {
+            if !self.channels[2].enabled {
+                self.wave_ram[i - 0x20] = v;
+            } else if self.cgb || self.wave_accessed {
+                self.wave_ram[self.channels[2].position / 2] = v;
+            }
+            return;
+        }
+        if i == NR52 {
+            self.write_power(v.get_bit(7));
+            return;
+        }
+        if !self.power {
+            // DMG length counters keep working while the APU is off
+            if !self.cgb {
+                if let NR11 | NR21 | NR31 | NR41 = i {
+                    self.load_length(i / 5, v);
+                }
+            }
+            return;
+        }
+        if i > NR52 {
+            return;
+        }
+
+        let old = self.regs[i];
+        self.regs[i] = v;
+        match i {
+            // leaving negate mode after a negated calculation disables channel 1
+            NR10 if self.sweep_negated && old.get_bit(3) && !v.get_bit(3) => self.channels[0].enabled = false,
+            NR11 | NR21 | NR31 | NR41 => self.load_length(i / 5, v),
+            NR12 | NR22 | NR30 | NR42 if !self.dac_enabled(i / 5) => self.channels[i / 5].enabled = false,
+            NR14 | NR24 | NR34 | NR44 => self.write_control(i / 5, v),
+            _ => {}
+        }
+    }
+
+    fn load_length(&mut self, n: usize, v: u8) {
+        self.channels[n].length = if n == 2 { 256 - v as usize } else { 64 - (v & 0x3f) as usize };
+    }
+
+    fn write_power(&mut self, on: bool) {
+        if on && !self.power {
+            self.frame_step = 0;
+            for c in self.channels.iter_mut() {
+                c.position = 0;
+            }
+            self.wave_sample = 0;
+        } else if !on && self.power {
+            self.regs = [0; 0x17];
+            for c in self.channels.iter_mut() {
+                let length = c.length;
+                *c = Channel::default();
+                if !self.cgb {
+                    c.length = length;
+                }
+            }
+        }
+        self.power = on;
+    }
+
+    // NRx4: bit 6 enables the length counter, bit 7 triggers the channel
+    fn write_control(&mut self, n: usize, v: u8) {
+        let max = if n == 2 { 256 } else { 64 };
+        // the length counter is clocked on even steps; in the other half of the period,
+        // enabling it or triggering with a zero length clocks it once more
+        let extra_clock = self.frame_step % 2 == 1;
+        let trigger = v.get_bit(7);
+
+        let c = &mut self.channels[n];
+        let was_enabled = c.length_enabled;
+        c.length_enabled = v.get_bit(6);
+        if extra_clock && !was_enabled && c.length_enabled && c.length > 0 {
+            c.length -= 1;
+            if c.length == 0 && !trigger {
+                c.enabled = false;
+            }
+        }
+
+        if trigger {
+            if c.length == 0 {
+                c.length = if extra_clock && c.length_enabled { max - 1 } else { max };
+            }
+            self.trigger(n);
+        }
+    }
+
+    fn trigger(&mut self, n: usize) {
+        // DMG: retriggering the wave channel while it reads a sample corrupts the start of wave RAM
+        if n == 2 && !self.cgb && self.channels[2].enabled && self.channels[2].timer == 2 {
+            let i = (self.channels[2].position + 1) % 32 / 2;
+            if i < 4 {
+                self.wave_ram[0] = self.wave_ram[i];
+            } else {
+                let block = i & !0b11;
+                self.wave_ram.copy_within(block..block + 4, 0);
+            }
+        }
+
+        let period = self.period(n);
+        let nrx2 = self.regs[n * 5 + 2];
+        let c = &mut self.channels[n];
+        c.enabled = true;
+        c.volume = nrx2 >> 4;
+        c.envelope_timer = nrx2 & 0b111;
+        match n {
+            // the first sample waits for the fetch
+            2 => {
+                c.timer = period + 6;
+                c.position = 0;
+            }
+            _ => c.timer = period,
+        }
+
+        match n {
+            0 => {
+                let nr10 = self.regs[NR10];
+                let (sweep_period, shift) = ((nr10 >> 4) & 0b111, nr10 & 0b111);
+                self.sweep_shadow = self.frequency(0) as u16;
+                self.sweep_timer = if sweep_period == 0 { 8 } else { sweep_period };
+                self.sweep_enabled = sweep_period != 0 || shift != 0;
+                self.sweep_negated = false;
+                if shift != 0 {
+                    self.sweep_frequency();
+                }
+            }
+            3 => self.lfsr = 0x7fff,
+            _ => {}
+        }
+
+        if !self.dac_enabled(n) {
+            self.channels[n].enabled = false;
+        }
+    }
+
+    // the next frequency, channel 1 stops when it overflows
+    fn sweep_frequency(&mut self) -> u16 {
+        let nr10 = self.regs[NR10];
+        let delta = self.sweep_shadow >> (nr10 & 0b111);
+        let frequency = if nr10.get_bit(3) {
+            self.sweep_negated = true;
+            self.sweep_shadow - delta
+        } else {
+            self.sweep_shadow + delta
+        };
+        if frequency > 2047 {
+            self.channels[0].enabled = false;
+        }
+        frequency
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+
+        let nr10 = self.regs[NR10];
+        let (sweep_period, shift) = ((nr10 >> 4) & 0b111, nr10 & 0b111);
+        self.sweep_timer = if sweep_period == 0 { 8 } else { sweep_period };
+        if !self.sweep_enabled || sweep_period == 0 {
+            return;
+        }
+
+        let frequency = self.sweep_frequency();
+        if frequency <= 2047 && shift != 0 {
+            self.sweep_shadow = frequency;
+            self.regs[0x03] = frequency as u8;
+            self.regs[NR14] = self.regs[NR14] & !0b111 | (frequency >> 8) as u8;
+            self.sweep_frequency();
+        }
+    }
+
+    fn clock_length(&mut self) {
+        for c in self.channels.iter_mut() {
+            if c.length_enabled && c.length > 0 {
+                c.length -= 1;
+                if c.length == 0 {
+                    c.enabled = false;
+                }
+            }
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        for n in [0, 1, 3] {
+            let nrx2 = self.regs[n * 5 + 2];
+            let period = nrx2 & 0b111;
+            let c = &mut self.channels[n];
+            if period == 0 {
+                continue;
+            }
+            c.envelope_timer = c.envelope_timer.saturating_sub(1);
+            if c.envelope_timer == 0 {
+                c.envelope_timer = period;
+                if nrx2.get_bit(3) && c.volume < 15 {
+                    c.volume += 1;
+                } else if !nrx2.get_bit(3) && c.volume > 0 {
+                    c.volume -= 1;
+                }
+            }
+        }
+    }
+
+    /// Steps the frame sequencer, on the falling edge of DIV bit 4 (bit 5 in double speed).
+    pub fn frame_sequencer(&mut self) {
+        if !self.power {
+            return;
+        }
+        match self.frame_step {
+            0 | 4 => self.clock_length(),
+            2 | 6 => {
+                self.clock_length();
+                self.clock_sweep();
+            }
+            7 => self.clock_envelope(),
+            _ => {}
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    /// Runs the channel timers for `cycles` T-cycles of the normal speed clock.
+    pub fn step(&mut self, cycles: usize) {
+        self.wave_accessed = false;
+        if self.power {
+            self.run_timers(cycles);
+        }
+        if self.resampler.is_some() {
+            let output = self.output();
+            if let Some(resampler) = &mut self.resampler {
+                resampler.add(cycles, output);
+            }
+        }
+    }
+
+    fn run_timers(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            for n in 0..4 {
+                let timer = self.channels[n].timer.saturating_sub(1);
+                self.channels[n].timer = if timer > 0 { timer } else { self.period(n) };
+                if timer > 0 {
+                    continue;
+                }
+                match n {
+                    0 | 1 => {
+                        let c = &mut self.channels[n];
+                        c.position = (c.position + 1) % 8;
+                    }
+                    2 => {
+                        let c = &mut self.channels[2];
+                        c.position = (c.position + 1) % 32;
+                        let byte = self.wave_ram[c.position / 2];
+                        self.wave_sample = if c.position.is_multiple_of(2) { byte >> 4 } else { byte & 0xf };
+                        self.wave_accessed = c.enabled;
+                    }
+                    _ => {
+                        if self.regs[NR43] >> 4 < 14 {
+                            let bit = (self.lfsr ^ self.lfsr >> 1) & 1;
+                            self.lfsr = self.lfsr >> 1 | bit << 14;
+                            if self.regs[NR43].get_bit(3) {
+                                self.lfsr = self.lfsr & !(1 << 6) | bit << 6;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // the 0-15 value a channel sends to its DAC
+    fn digital(&self, n: usize) -> u8 {
+        let c = &self.channels[n];
+        if !c.enabled {
+            return 0;
+        }
+        match n {
+            0 | 1 => {
+                let duty = DUTIES[(self.regs[n * 5 + 1] >> 6) as usize];
+                (duty >> (7 - c.position) & 1) * c.volume
+            }
+            2 => match (self.regs[NR32] >> 5) & 0b11 {
+                0 => 0,
+                shift => self.wave_sample >> (shift - 1),
+            },
+            _ => (!self.lfsr & 1) as u8 * c.volume,
+        }
+    }
+
+    /// The left and right output, each in -1.0-1.0, after NR51 panning and NR50 volume.
+    pub fn output(&self) -> (f32, f32) {
+        let nr50 = self.regs[NR50];
+        let nr51 = self.regs[NR51];
+        let (mut left, mut right) = (0.0, 0.0);
+        for n in 0..4 {
+            if !self.dac_enabled(n) {
+                continue;
+            }
+            let analog = self.digital(n) as f32 / 7.5 - 1.0;
+            if nr51.get_bit(n + 4) {
+                left += analog;
+            }
+            if nr51.get_bit(n) {
+                right += analog;
+            }
+        }
+        let left_volume = ((nr50 >> 4) & 0b111) as f32 + 1.0;
+        let right_volume = (nr50 & 0b111) as f32 + 1.0;
+        (left / 4.0 * left_volume / 8.0, right / 4.0 * right_volume / 8.0)
+    }
+
+    /// Starts collecting samples at `rate` Hz, see `samples`.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.resampler = Some(Resampler::new(rate));
+    }
+
+    /// Takes the interleaved left and right samples produced since the last call, usually once a frame.
+    /// Empty until `set_sample_rate` is called. Samples pile up until taken, so call it regularly
+    /// even while the LCD is off and no frame arrives.
+    pub fn samples(&mut self) -> Vec<f32> {
+        self.resampler.as_mut().map_or_else(Vec::new, |r| std::mem::take(&mut r.samples))
+    }
+
+    /// `samples` as 16-bit integers.
+    pub fn samples_i16(&mut self) -> Vec<i16> {
+        self.samples().iter().map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(apu: &APU) -> u8 {
+        apu.read(0xff26)
+    }
+
+    // channel 1 with its DAC on and a length of `length`, triggered without the length counter
+    fn start_channel1(apu: &mut APU, length: u8) {
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff11, 64 - length);
+        apu.write(0xff14, 0x80);
+    }
+
+    #[test]
+    fn nr52_power_and_channel_status() {
+        let mut apu = APU::new(false);
+        assert_eq!(status(&apu), 0xf0);
+        start_channel1(&mut apu, 64);
+        assert_eq!(status(&apu), 0xf1);
+
+        apu.write(0xff26, 0x00);
+        assert_eq!(status(&apu), 0x70);
+        // registers are cleared and ignore writes while the power is off
+        apu.write(0xff12, 0xf0);
+        assert_eq!(apu.read(0xff12), 0x00);
+        assert_eq!(apu.read(0xff10), 0x80);
+
+        apu.write(0xff26, 0x80);
+        assert_eq!(status(&apu), 0xf0);
+    }
+
+    #[test]
+    fn unused_bits_read_as_1() {
+        let mut apu = APU::new(false);
+        for i in 0xff10..0xff26 {
+            apu.write(i, 0x00);
+        }
+        for i in 0xff10..0xff26 {
+            assert_eq!(apu.read(i), READ_MASKS[(i - 0xff10) as usize], "{:04x}", i);
+        }
+        assert_eq!(apu.read(0xff27), 0xff);
+        assert_eq!(apu.read(0xff2f), 0xff);
+    }
+
+    #[test]
+    fn length_counter_stops_the_channel() {
+        let mut apu = APU::new(false);
+        start_channel1(&mut apu, 2);
+        apu.write(0xff14, 0x40);
+        // steps 0 and 2 clock the length counter
+        apu.frame_sequencer();
+        assert_eq!(status(&apu) & 1, 1);
+        apu.frame_sequencer();
+        apu.frame_sequencer();
+        assert_eq!(status(&apu) & 1, 0);
+    }
+
+    #[test]
+    fn enabling_length_in_the_second_half_clocks_it_once_more() {
+        let mut apu = APU::new(false);
+        start_channel1(&mut apu, 2);
+        // the next step, 1, doesn't clock the length counter
+        apu.frame_sequencer();
+        apu.write(0xff14, 0x40);
+        assert_eq!(status(&apu) & 1, 1);
+        apu.write(0xff14, 0x00);
+        apu.write(0xff14, 0x40);
+        assert_eq!(status(&apu) & 1, 0);
+    }
+
+    #[test]
+    fn enabling_length_in_the_first_half_doesnt() {
+        let mut apu = APU::new(false);
+        start_channel1(&mut apu, 1);
+        apu.write(0xff14, 0x40);
+        apu.write(0xff14, 0x00);
+        apu.write(0xff14, 0x40);
+        assert_eq!(status(&apu) & 1, 1);
+    }
+}
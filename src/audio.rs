@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// the APU clock, T-cycles per second at normal speed
+pub const CLOCK_RATE: f64 = 4194304.0;
+
+const TAPS: usize = 16;
+const PHASES: usize = 64;
+// fraction of the host Nyquist frequency that is kept
+const CUTOFF: f64 = 0.9;
+
+/// Turns the APU output into interleaved stereo samples at the host rate.
+/// Every change of the output is added as a band-limited step, so square waves don't alias.
+#[derive(Debug)]
+pub struct Resampler {
+    pub rate: u32,
+    pub samples: Vec<f32>,
+
+    kernel: Vec<[f32; TAPS]>,
+    // output samples per T-cycle, and the position between two output samples
+    step: f64,
+    time: f64,
+    last: [f32; 2],
+    deltas: VecDeque<[f32; 2]>,
+    level: [f32; 2],
+    // the DC blocking capacitor of the output stage
+    capacitor: [f32; 2],
+    charge: f32,
+}
+
+impl Resampler {
+    pub fn new(rate: u32) -> Resampler {
+        let kernel = (0..PHASES)
+            .map(|p| {
+                let offset = p as f64 / PHASES as f64;
+                let mut h = [0.0; TAPS];
+                for (k, v) in h.iter_mut().enumerate() {
+                    let x = k as f64 - (TAPS / 2) as f64 - offset;
+                    if x.abs() >= (TAPS / 2) as f64 {
+                        continue;
+                    }
+                    let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+                    let window = 0.42 + 0.5 * (2.0 * PI * x / TAPS as f64).cos() + 0.08 * (4.0 * PI * x / TAPS as f64).cos();
+                    *v = sinc * window;
+                }
+                let sum: f64 = h.iter().sum();
+                h.map(|v| (v / sum) as f32)
+            })
+            .collect();
+
+        Resampler {
+            rate,
+            samples: vec![],
+            kernel,
+            step: rate as f64 / CLOCK_RATE,
+            time: 0.0,
+            last: [0.0; 2],
+            deltas: VecDeque::from(vec![[0.0; 2]; TAPS]),
+            level: [0.0; 2],
+            capacitor: [0.0; 2],
+            charge: 0.999958f32.powf((CLOCK_RATE / rate as f64) as f32),
+        }
+    }
+
+    /// Adds `cycles` T-cycles of `output`, the left and right level.
+    pub fn add(&mut self, cycles: usize, output: (f32, f32)) {
+        let output = [output.0, output.1];
+        let phase = (self.time * PHASES as f64) as usize;
+        for c in 0..2 {
+            let delta = output[c] - self.last[c];
+            if delta != 0.0 {
+                for (d, h) in self.deltas.iter_mut().zip(self.kernel[phase].iter()) {
+                    d[c] += delta * h;
+                }
+            }
+        }
+        self.last = output;
+
+        self.time += cycles as f64 * self.step;
+        while self.time >= 1.0 {
+            self.time -= 1.0;
+            let delta = self.deltas.pop_front().unwrap();
+            self.deltas.push_back([0.0; 2]);
+            for ((level, capacitor), delta) in self.level.iter_mut().zip(self.capacitor.iter_mut()).zip(delta) {
+                *level += delta;
+                let out = *level - *capacitor;
+                *capacitor = *level - out * self.charge;
+                self.samples.push(out);
+            }
+        }
+    }
+}
+
+/// 16-bit stereo PCM WAV file. The sizes in the header are filled in by `finish`, or when dropped.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, rate: u32) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // PCM, 2 channels, the sample rate, bytes per second, bytes per frame and bits per sample
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * 4).to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { file, data_len: 0 })
+    }
+
+    /// Appends interleaved left and right samples.
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for s in samples {
+            self.file.write_all(&s.to_le_bytes())?;
+        }
+        self.data_len += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resampler_outputs_the_host_rate() {
+        for rate in [44100, 48000] {
+            let mut resampler = Resampler::new(rate);
+            // one second, in the 4 T-cycle steps of the APU
+            for i in 0..CLOCK_RATE as usize / 4 {
+                resampler.add(4, if i / 1000 % 2 == 0 { (1.0, 1.0) } else { (-1.0, -1.0) });
+            }
+            let frames = resampler.samples.len() / 2;
+            assert_eq!(resampler.samples.len() % 2, 0);
+            assert!(frames.abs_diff(rate as usize) <= 1, "{} frames at {} Hz", frames, rate);
+        }
+    }
+
+    #[test]
+    fn wav_header_sizes() {
+        let path = std::env::temp_dir().join(format!("gbe-rs-test-{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path, 48000).unwrap();
+        wav.write(&[1, -1, 2, -2]).unwrap();
+        wav.write(&[3, -3]).unwrap();
+        wav.finish().unwrap();
+        drop(wav);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(u32_at(4), 36 + 12);
+        assert_eq!(u32_at(24), 48000);
+        assert_eq!(u32_at(40), 12);
+        assert_eq!(bytes[44..48], [1, 0, 0xff, 0xff]);
+    }
+}
@@ -1,6 +1,9 @@
+use crate::apu::APU;
 use crate::logger::Logger;
 use crate::ppu::PPU;
 use crate::ram::Reg;
+use crate::serial::Serial;
+use crate::sgb::SGB;
 
 use std::fmt;
 use std::fmt::Write;
@@ -27,7 +30,7 @@ pub struct CPULog {
     pub halting: bool,
 
     pub cycle: usize,
-    pub sys_counter: usize,
+    pub sys_counter: u16,
     pub exe_counter: usize,
 
     pub reg_if: u8,
@@ -232,6 +235,9 @@ pub struct CPU {
     pub ppu: PPU,
     pub cpu_logger: Logger<CPULog>,
     pub serial_logger: Logger<u8>,
+    pub serial_device: Option<Serial>,
+    pub sgb: Option<SGB>,
+    pub apu: APU,
 
     pub joypad_buffer: u8,
 
@@ -249,22 +255,38 @@ pub struct CPU {
 
     pub halting: bool,
     pub ime: bool,
+    pub ime_scheduled: bool,
 
     pub cycle: usize,
-    pub sys_counter: usize,
+    pub sys_counter: u16,
     pub exe_counter: usize,
+
+    pub tima_overflow: bool,
+    pub tima_reloading: bool,
+    pub serial_bits: u8,
+
+    pub double_speed: bool,
+    pub hdma_active: bool,
+    pub hdma_src: u16,
+    pub hdma_dst: u16,
 }
 
 impl CPU {
     pub fn new(ppu: PPU) -> Self {
+        // CGB software checks A == 0x11 after boot to detect the color hardware
+        let a = if ppu.cgb || ppu.dmg_compat { 0x11 } else { 0 };
+        let apu = APU::new(ppu.cgb);
         CPU {
             ppu: ppu,
             cpu_logger: Logger::new(0x1000),
             serial_logger: Logger::new(0x1000),
+            serial_device: None,
+            sgb: None,
+            apu,
 
-            joypad_buffer: 0b111111,
+            joypad_buffer: 0b11111111,
 
-            a: 0,
+            a,
             f: 0,
             b: 0,
             c: 0,
@@ -276,15 +298,23 @@ impl CPU {
             pc: 0x100,
             halting: false,
             ime: false,
+            ime_scheduled: false,
             cycle: 0,
             sys_counter: 0,
             exe_counter: 0,
+            tima_overflow: false,
+            tima_reloading: false,
+            serial_bits: 0,
+            double_speed: false,
+            hdma_active: false,
+            hdma_src: 0,
+            hdma_dst: 0,
         }
     }
 
     fn log(&mut self, instr: &str, op1: OP, op2: OP, info: LogInfo) {
         if self.cpu_logger.logging {
-            let codes = (0..3).map(|n| self.read(self.pc - 1 + n)).collect();
+            let codes = (0..3).map(|n| self.ppu.mbc.read(self.pc - 1 + n)).collect();
             let c = CPULog {
                 a: self.a,
                 f: self.f,
@@ -386,12 +416,31 @@ impl CPU {
 
     #[inline]
     fn read(&mut self, i: u16) -> u8 {
-        self.ppu.mbc.read(i)
+        let v = match i {
+            0xff10..=0xff3f => self.apu.read(i),
+            0xff76 | 0xff77 if self.ppu.cgb => self.apu.read_pcm(i),
+            _ => self.ppu.mbc.read(i),
+        };
+        self.tick();
+        v
     }
 
     #[inline]
     fn write(&mut self, i: u16, v: u8) {
-        self.ppu.mbc.write(i, v);
+        match i {
+            0xff00 => self.write_joypad(v),
+            0xff02 => self.write_serial_control(v),
+            0xff04 => self.write_div(),
+            0xff05 => self.write_tima(v),
+            0xff06 => self.write_tma(v),
+            0xff07 => self.write_tac(v),
+            0xff10..=0xff3f => self.apu.write(i, v),
+            0xff41 => self.ppu.write_stat(v),
+            0xff4d if self.ppu.cgb => self.write_key1(v),
+            0xff55 if self.ppu.cgb => self.write_hdma(v),
+            _ => self.ppu.mbc.write(i, v),
+        }
+        self.tick();
     }
 
     #[inline]
@@ -412,12 +461,24 @@ impl CPU {
     #[inline]
     fn tick(&mut self) {
         self.cycle += 1;
+
+        let counter = self.sys_counter;
+        for n in 0 .. 4 {
+            self.sys_counter = self.sys_counter.wrapping_add(1);
+            // in double speed mode the PPU keeps its pace while everything else runs twice as fast
+            if !self.double_speed || n % 2 == 0 {
+                self.ppu.step();
+            }
+            self.joypad();
+        }
+        self.serial(counter);
+        self.timer(counter);
+        self.sound(counter);
     }
 
     fn fetch8(&mut self) -> u8 {
         let v = self.read(self.pc);
         self.pc += 1;
-        self.tick();
         v
     }
 
@@ -523,7 +584,6 @@ impl CPU {
                 let i = self.fetch16();
                 self.write(i, bs[1]);
                 self.write(i + 1, bs[0]);
-                self.cycle -= 1;
             }
             OP::SP => self.sp = v,
             _ => panic!("CPU::store16 unexpected {}", op),
@@ -531,15 +591,13 @@ impl CPU {
     }
 
     fn push8(&mut self, v: u8) {
-        self.sp -= 1;
+        self.sp = self.sp.wrapping_sub(1);
         self.write(self.sp, v);
-        self.tick();
     }
 
     fn pop8(&mut self) -> u8 {
         let v = self.read(self.sp);
-        self.sp += 1;
-        self.tick();
+        self.sp = self.sp.wrapping_add(1);
         v
     }
 
@@ -576,6 +634,9 @@ impl CPU {
         self.log("LD", op1, op2, LogInfo::None);
         let n = self.load16(op2);
         self.store16(op1, n);
+        if op1 == OP::SP && op2 == OP::HL {
+            self.tick();
+        }
     }
 
     fn ld16_hl_sp_n(&mut self)  {
@@ -710,6 +771,7 @@ impl CPU {
         self.set_carry(carry);
         self.set_half(half);
         self.set_negative(false);
+        self.tick();
     }
 
     fn add_sp_n(&mut self)  {
@@ -734,12 +796,14 @@ impl CPU {
         self.log("INC", op, OP::None, LogInfo::None);
         let a = self.load16(op).add_carry_half(1).0;
         self.store16(op, a);
+        self.tick();
     }
 
     fn dec16(&mut self, op: OP)  {
         self.log("DEC", op, OP::None, LogInfo::None);
         let a = self.load16(op).sub_carry_half(1).0;
         self.store16(op, a);
+        self.tick();
     }
 
     fn daa(&mut self)  {
@@ -784,11 +848,12 @@ impl CPU {
     fn di(&mut self)  {
         self.log("DI", OP::None, OP::None, LogInfo::None);
         self.ime = false;
+        self.ime_scheduled = false;
     }
 
     fn ei(&mut self)  {
         self.log("EI", OP::None, OP::None, LogInfo::None);
-        self.ime = true;
+        self.ime_scheduled = true;
     }
 
     fn halt(&mut self)  {
@@ -799,6 +864,13 @@ impl CPU {
     fn stop(&mut self)  {
         self.log("STOP", OP::None, OP::None, LogInfo::None);
         //self.halting = true;
+
+        // CGB: STOP with KEY1 bit 0 armed switches the CPU speed
+        if self.ppu.cgb && self.read_reg(Reg::KEY1).get_bit(0) {
+            self.double_speed = !self.double_speed;
+            self.write_reg(Reg::KEY1, 0b01111110 | (self.double_speed as u8) << 7);
+            self.write_div();
+        }
     }
 
     fn nop(&mut self)  {
@@ -822,7 +894,6 @@ impl CPU {
         let hl = self.get_hl();
         self.log("JP", OP::HL, OP::None, LogInfo::U16h(hl));
         self.pc = hl;
-        self.tick();
     }
 
     fn jr(&mut self, op: OP)  {
@@ -854,6 +925,9 @@ impl CPU {
 
     fn ret(&mut self, op: OP)  {
         self.log("RET", op, OP::None, LogInfo::None);
+        if op != OP::Always {
+            self.tick();
+        }
         if self.cond_flag(op) {
             self.pc = self.pop16();
             self.tick();
@@ -861,13 +935,8 @@ impl CPU {
     }
 
     fn reti(&mut self) {
-        let pc = self.pop16();
-
-        self.sp -= 2;
         self.log("RETI", OP::None, OP::None, LogInfo::U16h(self.pc));
-        self.sp += 2;
-
-        self.pc = pc;
+        self.pc = self.pop16();
         self.tick();
         self.ime = true;
     }
@@ -1545,98 +1614,286 @@ impl CPU {
     }
 
 
-    fn serial(&mut self) {
-        let mut sc = self.read_reg(Reg::SC);
-        if sc.get_bit(7) {
-            let clock_list: [usize; 4] = [512, 256, 16, 8];
-            let clock = clock_list[(sc & 0b11) as usize];
-            if self.sys_counter % clock == 0 {
-                let sb = self.read_reg(Reg::SB);
+    fn serial(&mut self, counter: u16) {
+        if let Some(device) = self.serial_device.as_mut() {
+            device.tick();
+        }
 
-                self.serial_logger.write(sb);
+        let mut sc = self.read_reg(Reg::SC);
+        if !sc.get_bit(7) {
+            return;
+        }
 
-                self.write_reg(Reg::SC, *sc.set_bit(7, false));
-                self.modify_reg(Reg::IF, |mut u| *u.set_bit(3, true));
+        let sb = self.read_reg(Reg::SB);
+        let out = sb.get_bit(7);
+        let bit = if sc.get_bit(0) {
+            // internal clock: 8192Hz, or 262144Hz with the CGB fast clock
+            let clock_bit = if self.ppu.cgb && sc.get_bit(1) { 3 } else { 8 };
+            if !counter.get_bit(clock_bit) || self.sys_counter.get_bit(clock_bit) {
+                return;
+            }
+            match self.serial_device.as_mut() {
+                Some(device) => device.exchange(out),
+                None => true,
             }
+        } else {
+            match self.serial_device.as_mut().and_then(|device| device.external(out)) {
+                Some(bit) => bit,
+                None => return,
+            }
+        };
+
+        self.write_reg(Reg::SB, sb << 1 | bit as u8);
+        self.serial_bits += 1;
+        if self.serial_bits == 8 {
+            self.serial_bits = 0;
+            self.write_reg(Reg::SC, *sc.set_bit(7, false));
+            self.modify_reg(Reg::IF, |mut u| *u.set_bit(3, true));
         }
     }
 
-    fn timer(&mut self) {
-        if self.sys_counter % 256 == 0 {
-            self.modify_reg(Reg::DIV, |u| u.overflowing_add(1).0);
+    fn write_serial_control(&mut self, v: u8) {
+        if v.get_bit(7) {
+            self.serial_bits = 0;
+            self.serial_logger.write(self.read_reg(Reg::SB));
         }
+        // the clock speed bit only exists on CGB
+        let unused = if self.ppu.cgb { 0b01111100 } else { 0b01111110 };
+        self.write_reg(Reg::SC, v | unused);
+    }
 
+    // TIMA is clocked by the falling edge of (TAC enable AND the selected sys_counter bit),
+    // so resetting DIV or rewriting TAC can also produce an increment.
+    fn timer_signal(&self, counter: u16) -> bool {
         let tac = self.read_reg(Reg::TAC);
-        if tac.get_bit(2) {
-            let clock_list: [usize; 4] = [1024, 16, 64, 256];
-            let clock = clock_list[(tac & 0b11) as usize];
-            if self.sys_counter % clock == 0 {
-                let (tima, carry) = self.read_reg(Reg::TIMA).overflowing_add(1);
-                if carry {
-                    self.modify_reg(Reg::IF, |mut u| *u.set_bit(2, true));
-                    self.write_reg(Reg::TIMA, self.read_reg(Reg::TMA));
-                } else {
-                    self.write_reg(Reg::TIMA, tima);
-                }
+        let bit_list: [usize; 4] = [9, 3, 5, 7];
+        tac.get_bit(2) && counter.get_bit(bit_list[(tac & 0b11) as usize])
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, carry) = self.read_reg(Reg::TIMA).overflowing_add(1);
+        self.write_reg(Reg::TIMA, tima);
+        if carry {
+            self.tima_overflow = true;
+        }
+    }
+
+    fn timer(&mut self, counter: u16) {
+        // TIMA stays 0x00 for one M-cycle after overflowing, then TMA is loaded
+        self.tima_reloading = false;
+        if self.tima_overflow {
+            self.tima_overflow = false;
+            self.tima_reloading = true;
+            self.write_reg(Reg::TIMA, self.read_reg(Reg::TMA));
+            self.modify_reg(Reg::IF, |mut u| *u.set_bit(2, true));
+        }
+
+        if self.timer_signal(counter) && !self.timer_signal(self.sys_counter) {
+            self.increment_tima();
+        }
+
+        self.write_reg(Reg::DIV, (self.sys_counter >> 8) as u8);
+    }
+
+    fn write_div(&mut self) {
+        let counter = self.sys_counter;
+        self.sys_counter = 0;
+        self.write_reg(Reg::DIV, 0);
+        if self.timer_signal(counter) {
+            self.increment_tima();
+        }
+        if counter.get_bit(self.frame_sequencer_bit()) {
+            self.apu.frame_sequencer();
+        }
+    }
+
+    // DIV bit 4, bit 5 in double speed, keeps the frame sequencer at 512Hz
+    fn frame_sequencer_bit(&self) -> usize {
+        if self.double_speed { 13 } else { 12 }
+    }
+
+    fn sound(&mut self, counter: u16) {
+        let bit = self.frame_sequencer_bit();
+        if counter.get_bit(bit) && !self.sys_counter.get_bit(bit) {
+            self.apu.frame_sequencer();
+        }
+        // the APU runs at the normal speed clock
+        self.apu.step(if self.double_speed { 2 } else { 4 });
+    }
+
+    fn write_tima(&mut self, v: u8) {
+        // a write while TIMA is 0x00 cancels the reload, a write during the reload is ignored
+        if !self.tima_reloading {
+            self.tima_overflow = false;
+            self.write_reg(Reg::TIMA, v);
+        }
+    }
+
+    fn write_tma(&mut self, v: u8) {
+        self.write_reg(Reg::TMA, v);
+        if self.tima_reloading {
+            self.write_reg(Reg::TIMA, v);
+        }
+    }
+
+    fn write_tac(&mut self, v: u8) {
+        let signal = self.timer_signal(self.sys_counter);
+        self.write_reg(Reg::TAC, v | 0b11111000);
+        if signal && !self.timer_signal(self.sys_counter) {
+            self.increment_tima();
+        }
+    }
+
+    fn write_key1(&mut self, v: u8) {
+        self.write_reg(Reg::KEY1, 0b01111110 | (self.double_speed as u8) << 7 | v & 1);
+    }
+
+    // HDMA5 bit 7 set starts an HBlank DMA, clear starts a general purpose DMA
+    // or cancels a running HBlank DMA. Bit 0-6 is the length in 16 byte blocks, minus 1
+    fn write_hdma(&mut self, v: u8) {
+        if self.hdma_active && !v.get_bit(7) {
+            self.hdma_active = false;
+            self.modify_reg(Reg::HDMA5, |u| u | 0x80);
+            return;
+        }
+
+        let src = u16::from_be_bytes([self.read_reg(Reg::HDMA1), self.read_reg(Reg::HDMA2)]);
+        let dst = u16::from_be_bytes([self.read_reg(Reg::HDMA3), self.read_reg(Reg::HDMA4)]);
+        self.hdma_src = src & 0xfff0;
+        self.hdma_dst = 0x8000 | dst & 0x1ff0;
+        self.write_reg(Reg::HDMA5, v & 0x7f);
+
+        if v.get_bit(7) {
+            self.hdma_active = true;
+        } else {
+            // the CPU is halted until the whole transfer is done
+            while !self.hdma_block() {}
+        }
+    }
+
+    // copies 16 bytes in 8 M-cycles, 16 in double speed mode. Returns true after the last block
+    fn hdma_block(&mut self) -> bool {
+        for i in 0..16 {
+            let v = self.ppu.mbc.read(self.hdma_src);
+            self.ppu.mbc.write(self.hdma_dst, v);
+            self.hdma_src = self.hdma_src.wrapping_add(1);
+            self.hdma_dst = 0x8000 | (self.hdma_dst + 1) & 0x1fff;
+            if self.double_speed || i % 2 == 1 {
+                self.tick();
             }
         }
+
+        let length = self.read_reg(Reg::HDMA5);
+        if length == 0 {
+            self.hdma_active = false;
+            self.write_reg(Reg::HDMA5, 0xff);
+            true
+        } else {
+            self.write_reg(Reg::HDMA5, length - 1);
+            false
+        }
     }
 
     fn joypad(&mut self) {
-        let jb = self.joypad_buffer;
+        let (jb, id) = match &self.sgb {
+            Some(sgb) => (sgb.joypad(self.joypad_buffer), sgb.joypad_id()),
+            None => (self.joypad_buffer, 0b1111),
+        };
         let jp = self.read_reg(Reg::JOYP);
+
+        // P14 selects the direction keys, P15 the buttons; both may be selected at once.
+        // With neither selected, an SGB in multiplayer mode answers the joypad ID
+        let mut lines = if jp & 0b110000 == 0b110000 { id } else { 0b1111 };
         if !jp.get_bit(4) {
-            self.write_reg(Reg::JOYP, 0b100000 | jb & 0b1111);
-            self.modify_reg(Reg::IF, |mut u| *u.set_bit(4, true));
+            lines &= jb & 0b1111;
         }
         if !jp.get_bit(5) {
-            self.write_reg(Reg::JOYP, 0b010000 | jb >> 4);
+            lines &= jb >> 4;
+        }
+
+        if jp & !lines & 0b1111 != 0 {
             self.modify_reg(Reg::IF, |mut u| *u.set_bit(4, true));
         }
+        self.write_reg(Reg::JOYP, 0b11000000 | jp & 0b110000 | lines);
+    }
+
+    fn write_joypad(&mut self, v: u8) {
+        if let Some(sgb) = &mut self.sgb {
+            sgb.write_joypad(v);
+        }
+        let jp = self.read_reg(Reg::JOYP);
+        self.write_reg(Reg::JOYP, 0b11000000 | v & 0b110000 | jp & 0b1111);
+    }
+
+    fn interrupt_request(&self) -> Option<(u16, usize)> {
+        let enable = self.read_reg(Reg::IE);
+        let request = self.read_reg(Reg::IF);
+        if enable.get_bit(0) && request.get_bit(0) {
+            Some((0x40, 0)) // VBlank
+        } else if enable.get_bit(1) && request.get_bit(1) {
+            Some((0x48, 1)) // LCD STAT
+        } else if enable.get_bit(2) && request.get_bit(2) {
+            Some((0x50, 2)) // Timer
+        } else if enable.get_bit(3) && request.get_bit(3) {
+            Some((0x58, 3)) // Serial
+        } else if enable.get_bit(4) && request.get_bit(4) {
+            Some((0x60, 4)) // Joypad
+        } else {
+            None
+        }
     }
 
     fn interrupt(&mut self) {
-        if self.read_reg(Reg::IE) & self.read_reg(Reg::IF) != 0 {
-            self.halting = false;
+        if self.interrupt_request().is_none() {
+            return;
         }
 
+        self.halting = false;
+
         if self.ime {
-            //self.halting = false;
-
-            let enable = self.read_reg(Reg::IE);
-            let request = self.read_reg(Reg::IF);
-            let (addr, n, _name) = if enable.get_bit(0) && request.get_bit(0) {
-                (0x40, 0, "VBlack")
-            } else if enable.get_bit(1) && request.get_bit(1) {
-                (0x48, 1, "LSTAT")
-            } else if enable.get_bit(2) && request.get_bit(2) {
-                (0x50, 2, "Timer")
-            } else if enable.get_bit(3) && request.get_bit(3) {
-                (0x58, 3, "Serial")
-            } else if enable.get_bit(4) && request.get_bit(4) {
-                (0x60, 4, "Joypad")
-            } else {
-                (0, 0, "")
-            };
+            self.ime = false;
+            self.tick();
+            self.tick();
 
-            if addr != 0 {
-                self.push16(self.pc);
-                self.pc = addr;
-                self.ime = false;
-                self.halting = false;
+            let bs = self.pc.to_be_bytes();
+            self.push8(bs[0]);
 
-                self.write_reg(Reg::IF, *self.read_reg(Reg::IF).set_bit(n, false));
+            // pushing the high byte may overwrite IE, so the vector is picked only now;
+            // if nothing is requested anymore the dispatch jumps to 0x0000
+            let request = self.interrupt_request();
+            self.push8(bs[1]);
 
-                self.tick();
-                self.tick();
-                self.tick();
-            }
+            self.pc = match request {
+                Some((addr, n)) => {
+                    self.write_reg(Reg::IF, *self.read_reg(Reg::IF).set_bit(n, false));
+                    addr
+                }
+                None => 0x0000,
+            };
+            self.tick();
         }
     }
 
     pub fn step(&mut self) {
         self.cycle = 0;
 
+        // EI takes effect after the instruction following it
+        if self.ime_scheduled {
+            self.ime_scheduled = false;
+            self.ime = true;
+        }
+
+        // stays set after the step that entered VBlank, so the frontend can pick up the frame
+        self.ppu.vblank_entered = false;
+
+        // HBlank DMA copies one block per HBlank, and pauses while the CPU is halted
+        if self.ppu.hblank_entered {
+            self.ppu.hblank_entered = false;
+            if self.hdma_active && !self.halting {
+                self.hdma_block();
+            }
+        }
+
         if self.halting {
             self.tick();
         } else {
@@ -1644,16 +1901,11 @@ impl CPU {
             self.exe_counter += 1;
         }
 
+        self.interrupt();
 
-        while self.cycle > 0 {
-            self.cycle -= 1;
-            for _ in 0 .. 4 {
-                self.ppu.step();
-                self.serial();
-                self.timer();
-                self.joypad();
-                self.interrupt();
-                self.sys_counter += 1;
+        if self.ppu.vblank_entered {
+            if let Some(sgb) = &mut self.sgb {
+                sgb.frame(&self.ppu.buffer);
             }
         }
     }
@@ -0,0 +1,158 @@
+use crate::cpu::CPU;
+use crate::ppu::PPU;
+use crate::sgb::{SGB_WIDTH, SGB_HEIGHT};
+
+pub const WIDTH: usize = 160;
+pub const HEIGHT: usize = 144;
+
+/// Colors of the four DMG shades, lightest first, as 0xRRGGBB.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DmgPalette {
+    Grey,
+    Green,
+    Pocket,
+    Light,
+    Custom([u32; 4]),
+}
+
+impl DmgPalette {
+    pub fn colors(&self) -> [u32; 4] {
+        match self {
+            DmgPalette::Grey => [0xffffff, 0xaaaaaa, 0x555555, 0x000000],
+            DmgPalette::Green => [0x9bbc0f, 0x8bac0f, 0x306230, 0x0f380f],
+            DmgPalette::Pocket => [0xc4cfa1, 0x8b956d, 0x4d533c, 0x1f1f1f],
+            DmgPalette::Light => [0x00b581, 0x009a71, 0x00694a, 0x004f3b],
+            DmgPalette::Custom(colors) => *colors,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PixelFormat {
+    // 0xAARRGGBB, what minifb and most window libraries take
+    Argb,
+    // 0xRRGGBBAA
+    Rgba,
+}
+
+/// Applied to every channel of the output, in the 0.0-1.0 range.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ColorCurve {
+    // values above 1.0 darken the midtones
+    Gamma(f32),
+    // 1.0 keeps the full range, lower values pull everything towards grey
+    Contrast(f32),
+    // the slow S-shaped transmittance of a passive LCD
+    LcdResponse,
+}
+
+impl ColorCurve {
+    fn apply(&self, c: f32) -> f32 {
+        match *self {
+            ColorCurve::Gamma(gamma) => c.powf(gamma),
+            ColorCurve::Contrast(contrast) => 0.5 + (c - 0.5) * contrast,
+            ColorCurve::LcdResponse => c * c * (3.0 - 2.0 * c),
+        }
+    }
+}
+
+/// Turns the PPU, CGB or SGB output into packed 32-bit pixels ready for a window or texture.
+#[derive(Debug)]
+pub struct FrameBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+
+    pub palette: DmgPalette,
+    pub format: PixelFormat,
+    // mimic the CGB LCD, whose colors are less saturated and bleed into each other
+    pub color_correction: bool,
+    // how much of the previous frame stays on screen, 0.0 turns LCD ghosting off
+    pub persistence: f32,
+    pub curves: Vec<ColorCurve>,
+
+    previous: Vec<[f32; 3]>,
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        FrameBuffer::new()
+    }
+}
+
+impl FrameBuffer {
+    pub fn new() -> FrameBuffer {
+        FrameBuffer {
+            width: WIDTH,
+            height: HEIGHT,
+            pixels: vec![0; WIDTH * HEIGHT],
+            palette: DmgPalette::Grey,
+            format: PixelFormat::Argb,
+            color_correction: false,
+            persistence: 0.0,
+            curves: vec![],
+            previous: vec![],
+        }
+    }
+
+    fn rgb555(&self, c: u16) -> [u8; 3] {
+        let r = (c & 0x1f) as u32;
+        let g = (c >> 5 & 0x1f) as u32;
+        let b = (c >> 10 & 0x1f) as u32;
+        if self.color_correction {
+            [
+                ((r * 26 + g * 4 + b * 2).min(960) >> 2) as u8,
+                ((g * 24 + b * 8).min(960) >> 2) as u8,
+                ((r * 6 + g * 4 + b * 22).min(960) >> 2) as u8,
+            ]
+        } else {
+            PPU::rgb555(c)
+        }
+    }
+
+    fn pack(&self, [r, g, b]: [u8; 3]) -> u32 {
+        let rgb = (r as u32) << 16 | (g as u32) << 8 | b as u32;
+        match self.format {
+            PixelFormat::Argb => 0xff000000 | rgb,
+            PixelFormat::Rgba => rgb << 8 | 0xff,
+        }
+    }
+
+    fn colors(&self, cpu: &CPU) -> Vec<[u8; 3]> {
+        if let Some(sgb) = &cpu.sgb {
+            sgb.buffer.iter().flatten().map(|&c| self.rgb555(c)).collect()
+        } else if cpu.ppu.cgb || cpu.ppu.dmg_compat {
+            cpu.ppu.buffer_cgb.iter().flatten().map(|&c| self.rgb555(c)).collect()
+        } else {
+            let palette = self.palette.colors();
+            cpu.ppu.buffer.iter().flatten().map(|&shade| {
+                let c = palette[shade as usize & 0b11];
+                [(c >> 16) as u8, (c >> 8) as u8, c as u8]
+            }).collect()
+        }
+    }
+
+    /// Converts the last frame of `cpu`. The size follows the output: 256x224 with an SGB.
+    pub fn update(&mut self, cpu: &CPU) {
+        (self.width, self.height) = if cpu.sgb.is_some() { (SGB_WIDTH, SGB_HEIGHT) } else { (WIDTH, HEIGHT) };
+        let colors = self.colors(cpu);
+
+        if self.previous.len() != colors.len() {
+            self.previous = colors.iter().map(|c| c.map(|v| v as f32)).collect();
+        }
+        let persistence = self.persistence.clamp(0.0, 1.0);
+        for (previous, c) in self.previous.iter_mut().zip(colors.iter()) {
+            for (p, &v) in previous.iter_mut().zip(c.iter()) {
+                *p = *p * persistence + v as f32 * (1.0 - persistence);
+            }
+        }
+
+        let mut curve = [0u8; 256];
+        for (i, out) in curve.iter_mut().enumerate() {
+            let c = self.curves.iter().fold(i as f32 / 255.0, |c, curve| curve.apply(c));
+            *out = (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+        }
+
+        self.pixels = self.previous.iter().map(|c| self.pack(c.map(|v| curve[v.round() as usize]))).collect();
+    }
+}
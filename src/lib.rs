@@ -3,4 +3,12 @@ pub mod rom;
 pub mod ram;
 pub mod mbc;
 pub mod ppu;
+pub mod colorize;
+pub mod serial;
+pub mod printer;
+pub mod sgb;
+pub mod apu;
+pub mod audio;
 pub mod cpu;
+pub mod framebuffer;
+pub mod viewer;
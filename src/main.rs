@@ -2,25 +2,27 @@ use gbe_rs::rom::read_rom;
 use gbe_rs::mbc::select_mbc;
 use gbe_rs::ppu::PPU;
 use gbe_rs::cpu::CPU;
+use gbe_rs::serial::{Serial, TcpLink};
+use gbe_rs::colorize::colorize;
+use gbe_rs::printer::Printer;
+use gbe_rs::sgb::SGB;
+use gbe_rs::framebuffer::{FrameBuffer, DmgPalette, ColorCurve};
+use gbe_rs::audio::WavWriter;
 
-use minifb::{Key, Window, WindowOptions, Scale};
+use minifb::{Key, KeyRepeat, Window, WindowOptions, Scale};
 
 use std::env;
+use std::path::PathBuf;
 
-const WIDTH: usize = 160;
-const HEIGHT: usize = 144;
-//const WIDTH: usize = 256;
-//const HEIGHT: usize = 256;
-
-fn display(mut cpu: CPU) {
-    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+fn display(mut cpu: CPU, mut frame: FrameBuffer, mut wav: Option<WavWriter>) {
+    frame.update(&cpu);
     let mut options = WindowOptions::default();
     //options.resize = true;
     options.scale = Scale::X4;
     let mut window = Window::new(
         "GBE.rs",
-        WIDTH,
-        HEIGHT,
+        frame.width,
+        frame.height,
         options,
     )
     .unwrap_or_else(|e| {
@@ -49,40 +51,114 @@ fn display(mut cpu: CPU) {
             }
         }
 
+        // F1 background, F2 window, F3 objects
+        for key in window.get_keys_pressed(KeyRepeat::No) {
+            match key {
+                Key::F1 => cpu.ppu.show_bg = !cpu.ppu.show_bg,
+                Key::F2 => cpu.ppu.show_window = !cpu.ppu.show_window,
+                Key::F3 => cpu.ppu.show_objects = !cpu.ppu.show_objects,
+                _ => {},
+            }
+        }
+
         cpu.joypad_buffer = joypad;
         cpu.step();
         n += 1;
 
-        if n >= 70224 {
+        // once per emulated frame, and every 70224 steps while the LCD is off,
+        // which shows the blank screen and keeps reading keys
+        if cpu.ppu.vblank_entered || n >= 70224 {
             n = 0;
-            let mut i: usize = 0;
-            for y in 0 .. HEIGHT {
-                for x in 0 .. WIDTH {
-                    buffer[i] = match cpu.ppu.buffer[y][x] {
-                        3 => 0x44444444,
-                        2 => 0x88888888,
-                        1 => 0xaaaaaaaa,
-                        0 => 0xeeeeeeee,
-                        _ => 0,
-                    };
-                    i += 1;
-                }
+            frame.update(&cpu);
+            if let Some(wav) = &mut wav {
+                wav.write(&cpu.apu.samples_i16()).unwrap();
             }
             window
-                .update_with_buffer(&buffer, WIDTH, HEIGHT)
+                .update_with_buffer(&frame.pixels, frame.width, frame.height)
                 .unwrap();
         }
     }
 }
 
+// buttons held while the CGB boot ROM runs, e.g. "up+a", in the joypad_buffer format
+fn parse_buttons(s: &str) -> u8 {
+    let mut buttons = 0b11111111;
+    for key in s.split('+').filter(|&k| k != "auto") {
+        buttons &= match key {
+            "right" => 0b11111110,
+            "left" => 0b11111101,
+            "up" => 0b11111011,
+            "down" => 0b11110111,
+            "a" => 0b11101111,
+            "b" => 0b11011111,
+            _ => panic!("unknown button {}", key),
+        };
+    }
+    buttons
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let rom = read_rom(args[1].clone()).unwrap();
     println!("{}", rom.title);
+    let supports_sgb = rom.supports_sgb();
 
-    let mut cpu = CPU::new(PPU::new(select_mbc(rom)));
+    let mut ppu = PPU::new(select_mbc(rom));
+    let mut serial_device: Option<Serial> = None;
+    let mut sgb = None;
+    let mut frame = FrameBuffer::new();
+    let mut wav_path: Option<PathBuf> = None;
+    let mut sample_rate = 44100;
+
+    // > cargo run --release rom.gb --listen 127.0.0.1:5000
+    // > cargo run --release rom.gb --connect 127.0.0.1:5000
+    // > cargo run --release rom.gb --printer ./prints
+    // > cargo run --release rom.gb --colorize auto
+    // > cargo run --release rom.gb --colorize left+a
+    // > cargo run --release rom.gb --sgb
+    // > cargo run --release rom.gb --palette green
+    // > cargo run --release rom.gb --color-correction
+    // > cargo run --release rom.gb --ghosting 0.5 --gamma 1.2 --contrast 0.8 --lcd-response
+    // > cargo run --release rom.gb --no-sprite-limit
+    // > cargo run --release rom.gb --wav session.wav --sample-rate 48000
+    let mut opts = args[2..].iter();
+    while let Some(opt) = opts.next() {
+        let mut value = || opts.next().unwrap_or_else(|| panic!("missing value for {}", opt));
+        match opt.as_str() {
+            "--listen" => serial_device = Some(Box::new(TcpLink::listen(value()).unwrap())),
+            "--connect" => serial_device = Some(Box::new(TcpLink::connect(value()).unwrap())),
+            "--printer" => serial_device = Some(Box::new(Printer::new(Some(value().into())).unwrap())),
+            "--colorize" => colorize(&mut ppu, parse_buttons(value())),
+            "--sgb" if supports_sgb => sgb = Some(SGB::new()),
+            "--sgb" => panic!("the cartridge does not support the Super Game Boy"),
+            "--palette" => frame.palette = match value().as_str() {
+                "grey" => DmgPalette::Grey,
+                "green" => DmgPalette::Green,
+                "pocket" => DmgPalette::Pocket,
+                "light" => DmgPalette::Light,
+                v => panic!("unknown palette {}", v),
+            },
+            "--color-correction" => frame.color_correction = true,
+            "--ghosting" => frame.persistence = value().parse().unwrap(),
+            "--gamma" => frame.curves.push(ColorCurve::Gamma(value().parse().unwrap())),
+            "--contrast" => frame.curves.push(ColorCurve::Contrast(value().parse().unwrap())),
+            "--lcd-response" => frame.curves.push(ColorCurve::LcdResponse),
+            "--no-sprite-limit" => ppu.unlimited_sprites = true,
+            "--wav" => wav_path = Some(value().into()),
+            "--sample-rate" => sample_rate = value().parse().unwrap(),
+            _ => panic!("unknown option {}", opt),
+        }
+    }
+
+    let mut cpu = CPU::new(ppu);
     cpu.cpu_logger.logging = false;
-    display(cpu);
+    cpu.serial_device = serial_device;
+    cpu.sgb = sgb;
+    let wav = wav_path.map(|path| {
+        cpu.apu.set_sample_rate(sample_rate);
+        WavWriter::create(&path, sample_rate).unwrap()
+    });
+    display(cpu, frame, wav);
     //loop {
     //    if cpu.exe_counter < 26000000 {
     //        cpu.step();
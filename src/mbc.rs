@@ -12,6 +12,7 @@ pub trait MBCTrait {
     fn modify_reg(&mut self, r: Reg, f: fn(u8) -> u8);
     fn get_rom(&self) -> &ROM;
     fn get_ram(&self) -> &RAM;
+    fn get_ram_mut(&mut self) -> &mut RAM;
     fn get_rom_bank(&self) -> usize;
     fn get_ram_ex_bank(&self) -> usize;
     fn set_vram_blocking(&mut self, b: bool);
@@ -20,8 +21,122 @@ pub trait MBCTrait {
 
 pub fn select_mbc(rom: ROM) -> MBC {
     match rom.rom_type.mbc_type {
+        MBCType::None => Box::new(NoMBC::new(rom)),
         MBCType::MBC1 => Box::new(MBC1::new(rom)),
-        _ => unimplemented!(),
+        MBCType::Unsupported(t) => panic!("unsupported cartridge type {:02x}", t),
+    }
+}
+
+/// 32 KiB ROM, with up to 8 KiB of RAM, and no banking.
+#[derive(Debug)]
+pub struct NoMBC {
+    pub rom: ROM,
+    pub ram: RAM,
+
+    pub vram_blocking: bool,
+    pub oam_blocking: bool,
+}
+
+impl NoMBC {
+    pub fn new(rom: ROM) -> NoMBC {
+        let ram = RAM::new(rom.ram_ex_size, rom.cgb_flag & 0x80 != 0);
+        NoMBC {
+            rom,
+            ram,
+            vram_blocking: false,
+            oam_blocking: false,
+        }
+    }
+}
+
+impl MBCTrait for NoMBC {
+    #[inline]
+    fn read_reg(&self, r: Reg) -> u8 {
+        self.ram.read_reg(r)
+    }
+
+    #[inline]
+    fn write_reg(&mut self, r: Reg, v: u8) {
+        self.ram.write_reg(r, v)
+    }
+
+    #[inline]
+    fn modify_reg(&mut self, r: Reg, f: fn(u8) -> u8) {
+        self.ram.modify_reg(r, f)
+    }
+
+    #[inline]
+    fn get_rom(&self) -> &ROM {
+        &self.rom
+    }
+
+    #[inline]
+    fn get_ram(&self) -> &RAM {
+        &self.ram
+    }
+
+    #[inline]
+    fn get_ram_mut(&mut self) -> &mut RAM {
+        &mut self.ram
+    }
+
+    #[inline]
+    fn get_rom_bank(&self) -> usize {
+        0x4000
+    }
+
+    #[inline]
+    fn get_ram_ex_bank(&self) -> usize {
+        0
+    }
+
+    #[inline]
+    fn set_oam_blocking(&mut self, b: bool) {
+        self.oam_blocking = b;
+    }
+
+    #[inline]
+    fn set_vram_blocking(&mut self, b: bool) {
+        self.vram_blocking = b;
+    }
+
+    fn read(&self, i: u16) -> u8 {
+        let i = i as usize;
+        match i {
+            0..=0x7fff => self.rom.read(i),
+            0xa000..=0xbfff => {
+                if i - 0xa000 < self.rom.ram_ex_size {
+                    self.ram.read_ex(i - 0xa000)
+                } else {
+                    0xff
+                }
+            }
+            _ => self.ram.read(i),
+        }
+    }
+
+    fn write(&mut self, i: u16, v: u8) {
+        let i = i as usize;
+        match i {
+            0..=0x7fff => {}
+            0x8000..=0x9fff => {
+                if !self.vram_blocking {
+                    self.ram.write(i, v);
+                }
+            }
+            0xa000..=0xbfff => {
+                if i - 0xa000 < self.rom.ram_ex_size {
+                    self.ram.write_ex(i - 0xa000, v);
+                }
+            }
+            0xff46 => {
+                if !self.oam_blocking {
+                    self.ram.transfer_dma(v as usize);
+                }
+                self.ram.write(i, v);
+            }
+            _ => self.ram.write(i, v),
+        }
     }
 }
 
@@ -44,7 +159,7 @@ pub struct MBC1 {
 
 impl MBC1 {
     pub fn new(rom: ROM) -> MBC1 {
-        let ram = RAM::new(rom.ram_ex_size);
+        let ram = RAM::new(rom.ram_ex_size, rom.cgb_flag & 0x80 != 0);
         MBC1 {
             rom: rom,
             ram: ram,
@@ -86,6 +201,11 @@ impl MBCTrait for MBC1 {
         &self.ram
     }
 
+    #[inline]
+    fn get_ram_mut(&mut self) -> &mut RAM {
+        &mut self.ram
+    }
+
     #[inline]
     fn get_rom_bank(&self) -> usize {
         self.rom_bank
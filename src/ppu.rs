@@ -5,6 +5,9 @@ use crate::ram::Reg;
 extern crate bit_field;
 use bit_field::BitField;
 
+use std::collections::VecDeque;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 enum Mode {
     OAMScan,
     Drawing,
@@ -12,28 +15,116 @@ enum Mode {
     VBlank,
 }
 
+#[derive(Debug, Clone, Copy)]
+struct Sprite {
+    i: usize,
+    y: usize,
+    x: usize,
+    tile: u8,
+    attr: u8,
+    // 8 or 16, as LCDC bit 2 was during the scan
+    len: usize,
+}
+
 pub struct PPU {
     pub mbc: MBC,
 
     pub buffer: [[u8; 160]; 144],
-    pub buffer_bg: [[u8; 256]; 256],
-    pub buffer_win: [[u8; 256]; 256],
-    pub buffer_obj: [[u8; 256]; 256],
-    pub buffer_vram: [[u8; 256]; 256],
+    // CGB mode output, RGB555
+    pub buffer_cgb: [[u16; 160]; 144],
 
     pub lx: usize,
+    pub lcd_on: bool,
+    pub cgb: bool,
+    // DMG cartridge on CGB hardware, colored through the CGB palettes
+    pub dmg_compat: bool,
+    // set on entering HBlank of a visible line, cleared by the CPU for HBlank DMA
+    pub hblank_entered: bool,
+    // set on entering VBlank, when `buffer` holds a finished frame
+    pub vblank_entered: bool,
+
+    // layer switches for debugging, they only change what ends up in the buffers
+    pub show_bg: bool,
+    pub show_window: bool,
+    pub show_objects: bool,
+    pub hidden_objects: [bool; 40],
+    // draws every object on a line instead of the first 10, mode 3 timing still follows the limit
+    pub unlimited_sprites: bool,
+
+    mode: Mode,
+    stat_line: bool,
+    // color id and CGB BG map attributes
+    fifo: VecDeque<(u8, u8)>,
+    fetch_step: usize,
+    fetch_x: usize,
+    fetch_tile: u8,
+    fetch_attr: u8,
+    fetch_lo: u8,
+    fetch_hi: u8,
+    lcd_x: usize,
+    fetch_delay: usize,
+    discard: usize,
+    window_active: bool,
+    // the WX the window started at, later WX writes don't move it
+    window_x: usize,
+    window_triggered: bool,
+    window_line: usize,
+    sprite_stall: usize,
+    sprite_xs: Vec<usize>,
+    sprite_tile: Option<usize>,
+    sprites: Vec<Sprite>,
+    // every object on the line, only used for display when `unlimited_sprites` is set
+    all_sprites: Vec<Sprite>,
+    line_bg: [u8; 160],
+    line_bg_attr: [u8; 160],
+    // OBP0, OBP1 and LCDC bit 1 as they were when each pixel was drawn
+    line_obp: [[u8; 2]; 160],
+    line_obj_enable: [bool; 160],
 }
  
 impl PPU {
     pub fn new(mbc: MBC) -> PPU {
+        let cgb = mbc.get_ram().cgb;
         PPU {
             mbc: mbc,
             buffer: [[0; 160]; 144],
-            buffer_bg: [[0; 256]; 256],
-            buffer_win: [[0; 256]; 256],
-            buffer_obj: [[0; 256]; 256],
-            buffer_vram: [[0; 256]; 256],
+            buffer_cgb: [[0; 160]; 144],
             lx: 0,
+            lcd_on: false,
+            cgb,
+            dmg_compat: false,
+            hblank_entered: false,
+            vblank_entered: false,
+            show_bg: true,
+            show_window: true,
+            show_objects: true,
+            hidden_objects: [false; 40],
+            unlimited_sprites: false,
+            mode: Mode::HBlank,
+            stat_line: false,
+            fifo: VecDeque::with_capacity(16),
+            fetch_step: 0,
+            fetch_x: 0,
+            fetch_tile: 0,
+            fetch_attr: 0,
+            fetch_lo: 0,
+            fetch_hi: 0,
+            lcd_x: 0,
+            fetch_delay: 0,
+            discard: 0,
+            window_active: false,
+            window_x: 0,
+            window_triggered: false,
+            window_line: 0,
+            sprite_stall: 0,
+            sprite_xs: Vec::with_capacity(10),
+            sprite_tile: None,
+            sprites: Vec::with_capacity(10),
+            all_sprites: Vec::with_capacity(40),
+            line_bg: [0; 160],
+            line_bg_attr: [0; 160],
+            line_obp: [[0; 2]; 160],
+            line_obj_enable: [false; 160],
         }
     }
 
@@ -42,6 +133,11 @@ impl PPU {
         self.mbc.read(i)
     }
 
+    #[inline]
+    fn read_vram(&self, bank: usize, i: u16) -> u8 {
+        self.mbc.get_ram().read_vram(bank, i as usize)
+    }
+
     #[inline]
     fn read_reg(&self, i: Reg) -> u8 {
         self.mbc.read_reg(i)
@@ -67,290 +163,340 @@ impl PPU {
         self.modify_reg(Reg::IF, |mut u| *u.set_bit(0, true));
     }
 
-    fn read_tile(&mut self, addr: u16) -> [[u8; 8]; 8] {
-        let mut tile = [[0; 8]; 8];
-        for y in 0..8 {
-            let t1 = self.read(addr + (y as u16) * 2);
-            let t2 = self.read(addr + (y as u16) * 2 + 1);
-            for x in 0..8 {
-                tile[y][7-x] = (t1 >> x & 1) | ((t2 >> x & 1) << 1);
-            }
+    // BG and OBJ palette RAM hold 8 palettes of 4 little-endian RGB555 colors
+    pub(crate) fn cgb_color(palettes: &[u8; 64], palette: u8, color_id: u8) -> u16 {
+        let i = ((palette & 0b111) * 8 + color_id * 2) as usize;
+        u16::from_le_bytes([palettes[i], palettes[i + 1]])
+    }
+
+    // RGB555 to 8 bits per channel, uncorrected
+    pub(crate) fn rgb555(c: u16) -> [u8; 3] {
+        [c & 0x1f, c >> 5 & 0x1f, c >> 10 & 0x1f].map(|v| (v << 3 | v >> 2) as u8)
+    }
+
+    pub(crate) fn read_tile_row(&self, bank: usize, addr: u16, row: usize) -> [u8; 8] {
+        let mut pixels = [0; 8];
+        let t1 = self.read_vram(bank, addr + (row as u16) * 2);
+        let t2 = self.read_vram(bank, addr + (row as u16) * 2 + 1);
+        for (x, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = (t1 >> (7 - x) & 1) | ((t2 >> (7 - x) & 1) << 1);
         }
 
-        tile
+        pixels
     }
 
-    fn adderssing_tile(&self, i: u8, is_obj: bool) -> u16 {
+    pub(crate) fn adderssing_tile(&self, i: u8, is_obj: bool) -> u16 {
         let lcdc = self.read_reg(Reg::LCDC);
         let adderssing_mode = lcdc.get_bit(4);
 
         if is_obj || adderssing_mode {
             0x8000 + (i as u16) * 16
         } else {
-            ((0x9000 as i32) + (i as i8 as i32 * 16)) as u16
+            (0x9000 + (i as i8 as i32 * 16)) as u16
         }
     }
 
-    fn draw_background(&mut self) {
-        let lcdc = self.read_reg(Reg::LCDC);
-        let bg_addr = if lcdc.get_bit(3) { 0x9c00 } else { 0x9800 };
-        let mut y = 0;
-        let mut x = 0;
-        for i in 0 .. 1024 {
-            let ti = self.read(bg_addr + i);
-            let addr = self.adderssing_tile(ti, false);
-            let tile = self.read_tile(addr);
-            for iy in 0..8 {
-                for ix in 0..8 {
-                    let color_id = tile[iy][ix];
-                    let color = (self.read_reg(Reg::BGP) >> (color_id * 2)) & 0b11;
-                    let yy = (y + iy) % 256;
-                    let xx = (x + ix) % 256;
-                    self.buffer_bg[yy][xx] = color;
+    fn start_line(&mut self) {
+        self.fifo.clear();
+        self.fetch_step = 0;
+        self.fetch_x = 0;
+        // the first tile is fetched twice, delaying the first pixel by 6 dots
+        self.fetch_delay = 6;
+        self.lcd_x = 0;
+        self.discard = (self.read_reg(Reg::SCX) % 8) as usize;
+        self.window_active = false;
+        self.sprite_stall = 0;
+        self.sprite_tile = None;
+
+        self.sprite_xs.clear();
+        if self.read_reg(Reg::LCDC).get_bit(1) {
+            self.sprite_xs.extend(self.sprites.iter().map(|s| s.x).filter(|&x| x < 168));
+            self.sprite_xs.sort();
+        }
+    }
+
+    // selects the first 10 objects in OAM order that cover this line, whatever their X,
+    // and with `unlimited_sprites` all of them for display
+    fn oam_scan(&mut self, ly: usize) {
+        // WY is latched: once LY has matched it, the window stays enabled for the rest of the frame
+        if ly == 0 {
+            self.window_triggered = false;
+            self.window_line = 0;
+        }
+        if ly == self.read_reg(Reg::WY) as usize {
+            self.window_triggered = true;
+        }
+
+        let obj_len = if self.read_reg(Reg::LCDC).get_bit(2) { 16 } else { 8 };
+        self.sprites.clear();
+        self.all_sprites.clear();
+        for i in 0..40 {
+            let o = 0xfe00 + i as u16 * 4;
+            let y = self.read(o) as usize;
+            if ly + 16 >= y && ly + 16 < y + obj_len {
+                let sprite = Sprite {
+                    i,
+                    y,
+                    x: self.read(o + 1) as usize,
+                    tile: self.read(o + 2),
+                    attr: self.read(o + 3),
+                    len: obj_len,
+                };
+                if self.sprites.len() < 10 {
+                    self.sprites.push(sprite);
+                } else if !self.unlimited_sprites {
+                    break;
+                }
+                if self.unlimited_sprites {
+                    self.all_sprites.push(sprite);
                 }
             }
+        }
+    }
 
-            x += 8;
-            if x >= 256 {
-                x = 0;
-                y += 8;
-                if y >= 256 {
-                    y = 0;
-                }
+    fn fetch(&mut self, ly: usize) {
+        let lcdc = self.read_reg(Reg::LCDC);
+        let (map_addr, x, y) = if self.window_active {
+            (if lcdc.get_bit(6) { 0x9c00 } else { 0x9800 }, self.fetch_x % 32, self.window_line)
+        } else {
+            let scx = self.read_reg(Reg::SCX) as usize;
+            let scy = self.read_reg(Reg::SCY) as usize;
+            (if lcdc.get_bit(3) { 0x9c00 } else { 0x9800 }, (scx / 8 + self.fetch_x) % 32, (ly + scy) % 256)
+        };
+
+        // CGB BG map attributes live in VRAM bank 1:
+        // bit 0-2 palette, bit 3 tile bank, bit 5 flip X, bit 6 flip Y, bit 7 priority over objects
+        let bank = self.fetch_attr.get_bit(3) as usize;
+        let row = if self.fetch_attr.get_bit(6) { 7 - y % 8 } else { y % 8 } as u16;
+        match self.fetch_step {
+            1 => {
+                let addr = map_addr + ((y / 8) * 32 + x) as u16;
+                self.fetch_tile = self.read_vram(0, addr);
+                self.fetch_attr = if self.cgb { self.read_vram(1, addr) } else { 0 };
             }
+            3 => {
+                let addr = self.adderssing_tile(self.fetch_tile, false);
+                self.fetch_lo = self.read_vram(bank, addr + row * 2);
+            }
+            5 => {
+                let addr = self.adderssing_tile(self.fetch_tile, false);
+                self.fetch_hi = self.read_vram(bank, addr + row * 2 + 1);
+            }
+            _ => {}
         }
 
-        let mut scy = self.read_reg(Reg::SCY) as usize;
-        for dy in 0..144 {
-            let mut scx = self.read_reg(Reg::SCX) as usize;
-            for dx in 0..160 {
-                self.buffer[dy][dx] = self.buffer_bg[scy % 256][scx % 256];
-                scx += 1;
+        if self.fetch_step >= 6 && self.fifo.is_empty() {
+            for i in 0..8 {
+                let i = if self.fetch_attr.get_bit(5) { i } else { 7 - i };
+                let color_id = (self.fetch_lo >> i & 1) | ((self.fetch_hi >> i & 1) << 1);
+                self.fifo.push_back((color_id, self.fetch_attr));
             }
-            scy += 1;
+            self.fetch_step = 0;
+            self.fetch_x += 1;
+        } else {
+            self.fetch_step += 1;
         }
     }
 
-    fn draw_window(&mut self) {
-        let lcdc = self.read_reg(Reg::LCDC);
-        let wy = self.read_reg(Reg::WY);
-        let wx = self.read_reg(Reg::WX).overflowing_sub(6).0;
+    // object fetch penalty: 6 dots, plus waiting for the background fetch of the tile under
+    // the object's leftmost pixel, once per tile
+    fn sprite_penalty(&mut self, x: usize) -> usize {
+        if x == 0 {
+            return 11;
+        }
+
+        let offset = if self.window_active {
+            x + 7 - self.window_x
+        } else {
+            x + self.read_reg(Reg::SCX) as usize
+        };
+        let tile = offset / 8;
+        let wait = if self.sprite_tile == Some(tile) { 0 } else { 5usize.saturating_sub(offset % 8) };
+        self.sprite_tile = Some(tile);
+        6 + wait
+    }
 
-        let win_enable = lcdc.get_bit(5);
-        if !win_enable { 
-            return; 
+    fn draw_dot(&mut self, ly: usize) {
+        if self.sprite_stall > 0 {
+            self.sprite_stall -= 1;
+            return;
         }
 
-        let win_addr = if lcdc.get_bit(6) { 0x9c00 } else { 0x9800 };
-        let mut y = wy as usize;
-        let mut x = wx as usize;
-        for i in 0 .. 1024 {
-            let ti = self.read(win_addr + i);
-            let addr = self.adderssing_tile(ti, false);
-            let tile = self.read_tile(addr);
-            for iy in 0..8 {
-                for ix in 0..8 {
-                    let color_id = tile[iy][ix];
-                    let color = (self.read_reg(Reg::BGP) >> (color_id * 2)) & 0b11;
-                    let yy = (y + iy) % 256;
-                    let xx = (x + ix) % 256;
-                    self.buffer_win[yy][xx] = color;
-                }
-            }
-            x += 8;
-            if x >= 256 {
-                x = 0;
-                y += 8;
-                if y >= 256 {
-                    y = 0;
-                }
+        if self.fetch_delay > 0 {
+            self.fetch_delay -= 1;
+            return;
+        }
+
+        // WX 0-6 starts the window at the left edge with its first 7 - WX pixels cut off,
+        // WX >= 167 never reaches a pixel
+        let lcdc = self.read_reg(Reg::LCDC);
+        let wx = self.read_reg(Reg::WX) as usize;
+        if !self.window_active && lcdc.get_bit(5) && self.window_triggered && self.lcd_x + 7 >= wx {
+            self.window_active = true;
+            self.fifo.clear();
+            self.fetch_step = 0;
+            self.fetch_x = 0;
+            self.discard = 7usize.saturating_sub(wx);
+            self.window_x = self.lcd_x + 7 - self.discard;
+        }
+
+        if self.discard == 0 {
+            if let Some(i) = self.sprite_xs.iter().position(|&x| x.max(8) - 8 == self.lcd_x) {
+                let x = self.sprite_xs.remove(i);
+                self.sprite_stall = self.sprite_penalty(x) - 1;
+                return;
             }
         }
 
-        for dy in 0..144 {
-            for dx in 0..160 {
-                let color = self.buffer_win[dy][dx];
-                if wy <= (dy as u8) && wx <= (dx as u8) {
-                    self.buffer[dy][dx] = color;
+        self.fetch(ly);
+
+        if let Some((color_id, attr)) = self.fifo.pop_front() {
+            // on DMG, LCDC bit 0 off blanks both the background and the window
+            let color_id = if lcdc.get_bit(0) || self.cgb { color_id } else { 0 };
+            let shown = if self.window_active { self.show_window } else { self.show_bg };
+            let color_id = if shown { color_id } else { 0 };
+            if self.discard > 0 {
+                self.discard -= 1;
+            } else {
+                if self.cgb {
+                    self.buffer_cgb[ly][self.lcd_x] = PPU::cgb_color(&self.mbc.get_ram().bg_palette, attr, color_id);
+                } else {
+                    let bgp = self.read_reg(Reg::BGP);
+                    let shade = (bgp >> (color_id * 2)) & 0b11;
+                    self.buffer[ly][self.lcd_x] = shade;
+                    if self.dmg_compat {
+                        self.buffer_cgb[ly][self.lcd_x] = PPU::cgb_color(&self.mbc.get_ram().bg_palette, 0, shade);
+                    }
                 }
+                self.line_bg[self.lcd_x] = color_id;
+                self.line_bg_attr[self.lcd_x] = attr;
+                self.line_obp[self.lcd_x] = [self.read_reg(Reg::OBP0), self.read_reg(Reg::OBP1)];
+                self.line_obj_enable[self.lcd_x] = lcdc.get_bit(1);
+                self.lcd_x += 1;
             }
         }
     }
 
-    fn draw_oam(&mut self) {
+    fn draw_oam(&mut self, ly: usize) {
         let lcdc = self.read_reg(Reg::LCDC);
 
-        let obj_enable = lcdc.get_bit(1);
-        if !obj_enable {
+        if !self.show_objects {
             return;
         }
 
-        let obj_size = 1 + lcdc.get_bit(2) as usize;
-        let obj_len = 8 * obj_size as usize;
+        // DMG: the smaller X wins, then the lower OAM index. CGB: only the OAM index counts,
+        // unless OPRI bit 0 selects the DMG order
+        let mut sprites = if self.unlimited_sprites { self.all_sprites.clone() } else { self.sprites.clone() };
+        if !self.cgb || self.read_reg(Reg::OPRI).get_bit(0) {
+            sprites.sort_by_key(|s| (s.x, s.i));
+        }
 
-        //let mut oy = 0;
-        //let mut ox = 0;
-        for i in 0..40 {
-            let o = 0xfe00 + i * 4;
-            let y = self.read(o) as usize;
-            let x = self.read(o + 1) as usize;
-            let t = self.read(o + 2);
-            let a = self.read(o + 3);
-
-            let flip_y = a.get_bit(6);
-            let flip_x = a.get_bit(5);
-            let dmg_palette = self.read_reg(if a.get_bit(4) { Reg::OBP1 } else { Reg::OBP0 });
-            //let cgb_palette_bank = a.get_bit(3);
-            //let cgb_palette = a.get_bits(0..=2);
-
-            let visible = y == 0 || y >= 160 || /*x <= 8 ||*/ x >= 168 || a.get_bit(7);
-
-            for z in 0..obj_size {
-                let zz = if flip_y && z == 0 { 1 } else if flip_y && z == 1 { 0 } else { z };
-                let ti = self.adderssing_tile(t + (zz as u8), true);
-                let tile = self.read_tile(ti);
-                for yy in 0..8 {
-                    for xx in 0..8 {
-                        let iy = if flip_y { 7 - yy } else { yy };
-                        let ix = if flip_x { 7 - xx } else { xx };
-                        let color_id = tile[iy][ix];
-                        let color = (dmg_palette >> (color_id * 2)) & 0b11;
-                        //let ci = (color_id * 2) as usize;
-                        //let color = dmg_palette.get_bits(ci..ci+1);
-                        //self.buffer_obj[oy+yy+z*8][ox+xx] = color_id;
-                        let yyy = y.overflowing_sub(obj_len).0 + yy + z * 8;
-                        let xxx = x - 8 + xx;
-                        if yyy < 144 && xxx < 160 && !visible && color_id != 0 {
-                            self.buffer[yyy][xxx] = color;
-                        }
+        let mut drawn = [false; 160];
+        for s in sprites.into_iter().filter(|s| !self.hidden_objects[s.i]) {
+            let flip_y = s.attr.get_bit(6);
+            let flip_x = s.attr.get_bit(5);
+            let bg_priority = s.attr.get_bit(7);
+
+            let row = ly + 16 - s.y;
+            let row = if flip_y { s.len - 1 - row } else { row };
+            let t = if s.len == 16 { (s.tile & 0xfe) | (row / 8) as u8 } else { s.tile };
+            let bank = if self.cgb { s.attr.get_bit(3) as usize } else { 0 };
+            let tile = self.read_tile_row(bank, self.adderssing_tile(t, true), row % 8);
+            for xx in 0..8 {
+                let color_id = tile[if flip_x { 7 - xx } else { xx }];
+                let x = (s.x + xx).wrapping_sub(8);
+                if x >= 160 || color_id == 0 || drawn[x] || !self.line_obj_enable[x] {
+                    continue;
+                }
+
+                // a higher priority object hidden behind the background still masks the others
+                drawn[x] = true;
+                if self.cgb {
+                    // LCDC bit 0 off gives objects priority over everything,
+                    // otherwise either the object or the BG map attribute can put the background on top
+                    let behind = lcdc.get_bit(0)
+                        && self.line_bg[x] != 0
+                        && (bg_priority || self.line_bg_attr[x].get_bit(7));
+                    if !behind {
+                        self.buffer_cgb[ly][x] = PPU::cgb_color(&self.mbc.get_ram().obj_palette, s.attr, color_id);
+                    }
+                } else if !bg_priority || self.line_bg[x] == 0 {
+                    let dmg_palette = self.line_obp[x][s.attr.get_bit(4) as usize];
+                    let shade = (dmg_palette >> (color_id * 2)) & 0b11;
+                    self.buffer[ly][x] = shade;
+                    if self.dmg_compat {
+                        let palette = s.attr.get_bit(4) as u8;
+                        self.buffer_cgb[ly][x] = PPU::cgb_color(&self.mbc.get_ram().obj_palette, palette, shade);
                     }
                 }
             }
-            //ox += 8;
-            //if ox == 256 {
-            //    ox = 0;
-            //    oy += 8 * (1 + obj_size as usize);
-            //    if oy == 256 {
-            //        oy = 0;
-            //    }
-            //}
         }
     }
 
-    #[allow(dead_code)]
     fn clear_buffer(&mut self) {
         for y in 0 .. 144 {
             for x in 0 .. 160 {
                 self.buffer[y][x] = 0;
-            }
-        }
-        for y in 0 .. 256 {
-            for x in 0 .. 256 {
-                self.buffer_obj[y][x] = 0;
-            }
-        }
-        for y in 0 .. 256 {
-            for x in 0 .. 256 {
-                self.buffer_win[y][x] = 0;
-            }
-        }
-        for y in 0 .. 256 {
-            for x in 0 .. 256 {
-                self.buffer_bg[y][x] = 0;
-            }
-        }
-        for y in 0 .. 256 {
-            for x in 0 .. 256 {
-                self.buffer_vram[y][x] = 0;
+                self.buffer_cgb[y][x] = 0x7fff;
             }
         }
     }
 
-    #[allow(dead_code)]
-    fn dump_vram(&mut self) {
-        let mut y: usize = 0;
-        let mut x: usize = 0;
-        let mut z: usize = 0;
-        for i in 0..512 { // VRAM Address: 0x8000 .. 0x9fff
-            let addr = 0x8000 + i * 16;
-            for j in 0 .. 8 {
-                let t1 = self.read(addr + (j as u16) * 2);
-                let t2 = self.read(addr + (j as u16) * 2 + 1);
-                for k in 0 .. 8 {
-                    let color_id = (t2 >> (7 - k) & 1) << 1 | (t1 >> (7 - k) & 1);
-                    self.buffer_vram[y+j][x+k] = color_id;
-                }
-            }
-
-            z += 1;
-
-            if z == 1 {
-                y += 8;
-            }
-
-            if z == 2 {
-                z = 0;
-                x += 8;
-                y -= 8;
-                if x >= self.buffer_vram[y].len() {
-                    x = 0;
-                    y += 16;
-                    if y >= self.buffer_vram.len() {
-                        y = 0;
-                    }
-                }
-            }
+    // all STAT sources are ORed into one line, only its rising edge requests the interrupt
+    fn update_stat(&mut self) {
+        let mut stat = self.read_reg(Reg::STAT);
+        let coincidence = self.read_reg(Reg::LY) == self.read_reg(Reg::LYC);
+        self.write_reg(Reg::STAT, *stat.set_bit(2, coincidence));
+
+        let line = (coincidence && stat.get_bit(6))
+            || (self.mode == Mode::HBlank && stat.get_bit(3))
+            || (self.mode == Mode::VBlank && stat.get_bit(4))
+            || (self.mode == Mode::OAMScan && stat.get_bit(5));
+        if line && !self.stat_line {
+            self.set_interrupt_stat();
         }
+        self.stat_line = line;
     }
 
-    fn draw(&mut self) {
-        //self.clear_buffer();
-        self.draw_background();
-        self.draw_window();
-        self.draw_oam();
-        //self.dump_vram();
-    }
-
-    fn compare_lyc(&mut self) {
-        let mut stat = self.read_reg(Reg::STAT);
-        let lyc = self.read_reg(Reg::LYC);
-        let ly = self.read_reg(Reg::LY);
+    pub fn write_stat(&mut self, v: u8) {
+        let stat = self.read_reg(Reg::STAT);
 
-        if lyc == ly {
-            self.write_reg(Reg::STAT, *stat.set_bit(2, true));
-            if stat.get_bit(6) {
-                self.set_interrupt_stat();
+        // DMG bug: for one cycle the write acts as if every source were enabled
+        if !self.cgb && !self.dmg_compat {
+            self.write_reg(Reg::STAT, 0b11111000 | stat & 0b111);
+            if self.lcd_on {
+                self.update_stat();
             }
         }
+
+        self.write_reg(Reg::STAT, 0b10000000 | v & 0b1111000 | stat & 0b111);
+        if self.lcd_on {
+            self.update_stat();
+        }
     }
 
     fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
         let mut stat = self.read_reg(Reg::STAT);
         match mode {
             Mode::HBlank => {
+                self.hblank_entered = true;
                 self.write_reg(Reg::STAT, *stat.set_bits(0..=1, 0));
                 self.mbc.set_vram_blocking(false);
                 self.mbc.set_oam_blocking(false);
-                if stat.get_bit(3) {
-                    self.set_interrupt_stat();
-                }
             },
             Mode::VBlank => {
                 self.write_reg(Reg::STAT, *stat.set_bits(0..=1, 1));
                 self.mbc.set_vram_blocking(false);
                 self.mbc.set_oam_blocking(false);
-                if stat.get_bit(4) {
-                    self.set_interrupt_stat();
-                }
                 self.set_interrupt_vblank();
+                self.vblank_entered = true;
             },
             Mode::OAMScan => {
+                self.oam_scan(self.read_reg(Reg::LY) as usize);
                 self.write_reg(Reg::STAT, *stat.set_bits(0..=1, 2));
                 self.mbc.set_vram_blocking(false);
                 self.mbc.set_oam_blocking(true);
-                if stat.get_bit(5) {
-                    self.set_interrupt_stat();
-                }
             },
             Mode::Drawing => {
                 self.write_reg(Reg::STAT, *stat.set_bits(0..=1, 3));
@@ -360,10 +506,37 @@ impl PPU {
        }
     }
 
+    fn lcd_off(&mut self) {
+        self.lcd_on = false;
+        self.lx = 0;
+        self.write_reg(Reg::LY, 0);
+        self.mode = Mode::HBlank;
+        self.modify_reg(Reg::STAT, |mut u| *u.set_bits(0..=1, 0));
+        self.mbc.set_vram_blocking(false);
+        self.mbc.set_oam_blocking(false);
+        self.clear_buffer();
+    }
+
+    // the first line after the LCD is turned on is 4 dots short and has no OAM scan mode
+    fn lcd_on(&mut self) {
+        self.lcd_on = true;
+        self.lx = 4;
+        self.oam_scan(0);
+    }
+
     pub fn step(&mut self) {
+        if !self.read_reg(Reg::LCDC).get_bit(7) {
+            if self.lcd_on {
+                self.lcd_off();
+            }
+            return;
+        } else if !self.lcd_on {
+            self.lcd_on();
+        }
+
         let mut ly = self.read_reg(Reg::LY);
 
-        if self.lx == 457 {
+        if self.lx == 456 {
             self.lx = 0;
             ly += 1;
 
@@ -372,7 +545,6 @@ impl PPU {
             }
 
             self.write_reg(Reg::LY, ly);
-            self.compare_lyc();
         }
 
         if ly <= 143 {
@@ -380,16 +552,25 @@ impl PPU {
                 self.set_mode(Mode::OAMScan);
             } else if self.lx == 80 {
                 self.set_mode(Mode::Drawing);
-            } else if self.lx == 252 {
-                self.set_mode(Mode::HBlank);
+                self.start_line();
             }
-        } else if ly == 144 {
-            if self.lx == 0 {
-                self.draw();
+
+            if self.mode == Mode::Drawing {
+                if self.lcd_x == 160 {
+                    if self.window_active {
+                        self.window_line += 1;
+                    }
+                    self.draw_oam(ly as usize);
+                    self.set_mode(Mode::HBlank);
+                } else {
+                    self.draw_dot(ly as usize);
+                }
             }
+        } else if ly == 144 && self.lx == 0 {
             self.set_mode(Mode::VBlank);
         }
 
+        self.update_stat();
         self.lx += 1;
     }
 }
@@ -0,0 +1,370 @@
+use crate::serial::SerialDevice;
+use crate::viewer;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const PRINTER_WIDTH: usize = 160;
+const PRINTER_BUFFER_SIZE: usize = 0x2000;
+// status inquiries answered with "busy" after a print command
+const PRINTER_BUSY_INQUIRIES: usize = 4;
+const PRINTER_SHADES: [u8; 4] = [0xff, 0xaa, 0x55, 0x00];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLo,
+    LengthHi,
+    Data,
+    ChecksumLo,
+    ChecksumHi,
+    Alive,
+    Status,
+}
+
+#[derive(Debug, Clone)]
+pub struct PrinterImage {
+    pub width: usize,
+    pub height: usize,
+    // 8-bit grayscale, palette applied
+    pub pixels: Vec<u8>,
+}
+
+/// Game Boy Printer, attached to the serial port as the clock slave.
+/// Every print command renders the buffered tiles into a `PrinterImage`, which is written
+/// as a PNG file when an output directory is set, or kept in `images` for the caller to take.
+#[derive(Debug)]
+pub struct Printer {
+    pub dir: Option<PathBuf>,
+    pub images: Vec<PrinterImage>,
+
+    // numbers the files in `dir`
+    printed: usize,
+    state: State,
+    bits: u8,
+    input: u8,
+    output: u8,
+    next_output: u8,
+
+    command: u8,
+    compression: bool,
+    length: usize,
+    packet: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+
+    buffer: Vec<u8>,
+    status: u8,
+    busy: usize,
+}
+
+impl Printer {
+    pub fn new(dir: Option<PathBuf>) -> io::Result<Printer> {
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Printer {
+            dir,
+            images: vec![],
+            printed: 0,
+            state: State::Magic1,
+            bits: 0,
+            input: 0,
+            output: 0,
+            next_output: 0,
+            command: 0,
+            compression: false,
+            length: 0,
+            packet: vec![],
+            checksum: 0,
+            received_checksum: 0,
+            buffer: vec![],
+            status: 0,
+            busy: 0,
+        })
+    }
+
+    fn receive(&mut self, b: u8) {
+        if matches!(self.state, State::Command | State::Compression | State::LengthLo | State::LengthHi | State::Data) {
+            self.checksum = self.checksum.wrapping_add(b as u16);
+        }
+
+        self.state = match self.state {
+            State::Magic1 => if b == 0x88 { State::Magic2 } else { State::Magic1 },
+            State::Magic2 => {
+                self.checksum = 0;
+                if b == 0x33 { State::Command } else { State::Magic1 }
+            }
+            State::Command => {
+                self.command = b;
+                self.packet.clear();
+                State::Compression
+            }
+            State::Compression => {
+                self.compression = b & 1 == 1;
+                State::LengthLo
+            }
+            State::LengthLo => {
+                self.length = b as usize;
+                State::LengthHi
+            }
+            State::LengthHi => {
+                self.length |= (b as usize) << 8;
+                if self.length == 0 { State::ChecksumLo } else { State::Data }
+            }
+            State::Data => {
+                self.packet.push(b);
+                if self.packet.len() == self.length { State::ChecksumLo } else { State::Data }
+            }
+            State::ChecksumLo => {
+                self.received_checksum = b as u16;
+                State::ChecksumHi
+            }
+            State::ChecksumHi => {
+                self.received_checksum |= (b as u16) << 8;
+                self.next_output = 0x81;
+                State::Alive
+            }
+            State::Alive => {
+                self.execute();
+                self.next_output = self.status;
+                State::Status
+            }
+            State::Status => {
+                self.next_output = 0;
+                State::Magic1
+            }
+        };
+    }
+
+    fn execute(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= 0b1;
+            return;
+        }
+        self.status &= !0b1;
+
+        match self.command {
+            // initialize
+            0x01 => {
+                self.buffer.clear();
+                self.busy = 0;
+                self.status = 0;
+            }
+            // print: sheets, margins, palette, exposure
+            0x02 => {
+                if self.packet.len() == 4 {
+                    self.print(self.packet[2]);
+                }
+                self.buffer.clear();
+                self.busy = PRINTER_BUSY_INQUIRIES;
+                self.status = 0b0110;
+            }
+            // image data
+            0x04 => {
+                let data = if self.compression { decompress(&self.packet) } else { self.packet.clone() };
+                self.buffer.extend_from_slice(&data);
+                self.buffer.truncate(PRINTER_BUFFER_SIZE);
+                if !self.buffer.is_empty() {
+                    self.status |= 0b1000;
+                }
+            }
+            // status inquiry
+            0x0f => {
+                if self.busy > 0 {
+                    self.busy -= 1;
+                    if self.busy == 0 {
+                        self.status &= !0b0110;
+                    }
+                }
+            }
+            _ => self.status |= 0b10000,
+        }
+    }
+
+    fn print(&mut self, palette: u8) {
+        let palette = if palette == 0 { 0xe4 } else { palette };
+        let tiles = self.buffer.len() / 16;
+        if tiles == 0 {
+            return;
+        }
+        let height = tiles.div_ceil(PRINTER_WIDTH / 8) * 8;
+        let mut pixels = vec![PRINTER_SHADES[0]; PRINTER_WIDTH * height];
+
+        for t in 0..tiles {
+            let tx = t % (PRINTER_WIDTH / 8) * 8;
+            let ty = t / (PRINTER_WIDTH / 8) * 8;
+            for y in 0..8 {
+                let lo = self.buffer[t * 16 + y * 2];
+                let hi = self.buffer[t * 16 + y * 2 + 1];
+                for x in 0..8 {
+                    let color_id = (lo >> (7 - x) & 1) | ((hi >> (7 - x) & 1) << 1);
+                    let shade = (palette >> (color_id * 2)) & 0b11;
+                    pixels[(ty + y) * PRINTER_WIDTH + tx + x] = PRINTER_SHADES[shade as usize];
+                }
+            }
+        }
+
+        let image = PrinterImage { width: PRINTER_WIDTH, height, pixels };
+        match &self.dir {
+            Some(dir) => {
+                let path = dir.join(format!("print_{:03}.png", self.printed));
+                if let Err(e) = write_png(&path, &image) {
+                    eprintln!("Printer: failed to write {}: {}", path.display(), e);
+                }
+            }
+            None => self.images.push(image),
+        }
+        self.printed += 1;
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, bit: bool) -> bool {
+        if self.bits == 0 {
+            self.output = self.next_output;
+        }
+
+        let out = self.output & 0x80 != 0;
+        self.output <<= 1;
+        self.input = self.input << 1 | bit as u8;
+        self.bits += 1;
+
+        if self.bits == 8 {
+            self.bits = 0;
+            self.receive(self.input);
+        }
+
+        out
+    }
+}
+
+// run-length encoding: a control byte with bit 7 set repeats the next byte (n & 0x7f) + 2 times,
+// otherwise n + 1 literal bytes follow
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let n = data[i];
+        i += 1;
+        if n & 0x80 != 0 {
+            if let Some(&b) = data.get(i) {
+                out.extend(std::iter::repeat_n(b, (n & 0x7f) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + n as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+pub fn write_png(path: &Path, image: &PrinterImage) -> io::Result<()> {
+    viewer::write_png(path, image.width, image.height, png::ColorType::Grayscale, &image.pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // clocks a byte out to the printer and returns the byte it answers with
+    fn send(printer: &mut Printer, b: u8) -> u8 {
+        (0..8).fold(0, |out, i| out << 1 | printer.exchange(b >> (7 - i) & 1 == 1) as u8)
+    }
+
+    // sends a whole packet and returns the alive and status bytes
+    fn packet(printer: &mut Printer, command: u8, compression: bool, data: &[u8], checksum_error: bool) -> (u8, u8) {
+        let mut bytes = vec![command, compression as u8, data.len() as u8, (data.len() >> 8) as u8];
+        bytes.extend_from_slice(data);
+        let checksum = bytes.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16)) ^ checksum_error as u16;
+
+        for b in [0x88, 0x33].iter().chain(bytes.iter()).chain(checksum.to_le_bytes().iter()) {
+            send(printer, *b);
+        }
+        (send(printer, 0), send(printer, 0))
+    }
+
+    // a 20 tile row: tile 0 in color 1, the rest in color 3
+    fn tile_row() -> Vec<u8> {
+        let mut data = [0xff, 0x00].repeat(8);
+        data.extend(std::iter::repeat_n(0xff, 19 * 16));
+        data
+    }
+
+    #[test]
+    fn decompress_runs_and_literals() {
+        assert_eq!(decompress(&[0x02, 1, 2, 3, 0x81, 9]), [1, 2, 3, 9, 9, 9]);
+        assert_eq!(decompress(&[0x80, 0xff, 0x00, 7]), [0xff, 0xff, 7]);
+    }
+
+    #[test]
+    fn init_answers_alive() {
+        let mut printer = Printer::new(None).unwrap();
+        assert_eq!(packet(&mut printer, 0x01, false, &[], false), (0x81, 0x00));
+    }
+
+    #[test]
+    fn data_and_print() {
+        let mut printer = Printer::new(None).unwrap();
+        packet(&mut printer, 0x01, false, &[], false);
+
+        // the first half of the row as is, the second half compressed
+        let row = tile_row();
+        assert_eq!(packet(&mut printer, 0x04, false, &row[..160], false), (0x81, 0x08));
+        let compressed = [[0x80 | 78, 0xff]; 2].concat();
+        assert_eq!(decompress(&compressed), row[160..]);
+        assert_eq!(packet(&mut printer, 0x04, true, &compressed, false), (0x81, 0x08));
+
+        // one sheet, no margins, palette 0xe4
+        assert_eq!(packet(&mut printer, 0x02, false, &[0x01, 0x00, 0xe4, 0x40], false), (0x81, 0x06));
+        assert_eq!(printer.images.len(), 1);
+        let image = &printer.images[0];
+        assert_eq!((image.width, image.height), (160, 8));
+        assert!(image.pixels[..8].iter().all(|&p| p == 0xaa));
+        assert!(image.pixels[8..160].iter().all(|&p| p == 0x00));
+    }
+
+    #[test]
+    fn status_stays_busy_after_print() {
+        let mut printer = Printer::new(None).unwrap();
+        packet(&mut printer, 0x04, false, &tile_row(), false);
+        packet(&mut printer, 0x02, false, &[0x01, 0x00, 0xe4, 0x40], false);
+
+        let statuses: Vec<u8> = (0..5).map(|_| packet(&mut printer, 0x0f, false, &[], false).1).collect();
+        assert_eq!(statuses, [0x06, 0x06, 0x06, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn bad_checksum_sets_status_bit_0() {
+        let mut printer = Printer::new(None).unwrap();
+        assert_eq!(packet(&mut printer, 0x04, false, &tile_row(), true), (0x81, 0x01));
+        assert_eq!(packet(&mut printer, 0x0f, false, &[], false), (0x81, 0x00));
+        assert!(printer.images.is_empty());
+    }
+
+    #[test]
+    fn print_without_data_is_skipped() {
+        let mut printer = Printer::new(None).unwrap();
+        assert_eq!(packet(&mut printer, 0x02, false, &[0x01, 0x00, 0xe4, 0x40], false), (0x81, 0x06));
+        assert!(printer.images.is_empty());
+        assert_eq!(printer.printed, 0);
+    }
+
+    #[test]
+    fn prints_go_to_the_directory() {
+        let dir = std::env::temp_dir().join(format!("gbe-rs-test-{}", std::process::id())).join("prints");
+        let mut printer = Printer::new(Some(dir.clone())).unwrap();
+        packet(&mut printer, 0x04, false, &tile_row(), false);
+        packet(&mut printer, 0x02, false, &[0x01, 0x00, 0xe4, 0x40], false);
+
+        assert!(printer.images.is_empty());
+        assert!(dir.join("print_000.png").exists());
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+}
@@ -3,6 +3,18 @@
 pub struct RAM {
     pub ram: Vec<u8>,
     pub ram_ex: Vec<u8>,
+
+    pub cgb: bool,
+    pub vram: Vec<u8>,
+    pub vram_bank: usize,
+    pub bg_palette: [u8; 64],
+    pub obj_palette: [u8; 64],
+    pub wram: Vec<u8>,
+    pub wram_bank: usize,
+
+    // infrared: light from another device, and whether our own LED shines back into the receiver
+    pub ir_light: bool,
+    pub ir_loopback: bool,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -54,29 +66,77 @@ pub enum Reg {
     BCPS = 0xff68,
     BCPD = 0xff69,
     OCPS = 0xff6a,
+    OCPD = 0xff6b,
     DMA = 0xff46,
+    KEY0 = 0xff4c,
+    KEY1 = 0xff4d,
     VBK = 0xff4f,
     HDMA1 = 0xff51,
     HDMA2 = 0xff52,
     HDMA3 = 0xff53,
     HDMA4 = 0xff54,
     HDMA5 = 0xff55,
+    RP = 0xff56,
+    OPRI = 0xff6c,
+    SVBK = 0xff70,
+    PCM12 = 0xff76,
+    PCM34 = 0xff77,
 
     IF = 0xff0f,
     IE = 0xffff,
 }
 
 impl RAM {
-    pub fn new(ram_ex_size: usize) -> Self {
-        RAM {
+    pub fn new(ram_ex_size: usize, cgb: bool) -> Self {
+        let mut ram = RAM {
             ram: vec![0; 0x10000],
             ram_ex: vec![0; ram_ex_size],
+            cgb,
+            vram: vec![0; 0x4000],
+            vram_bank: 0,
+            bg_palette: [0xff; 64],
+            obj_palette: [0xff; 64],
+            wram: vec![0; 0x8000],
+            wram_bank: 1,
+            ir_light: false,
+            ir_loopback: false,
+        };
+
+        // KEY0 and OPRI are locked by the boot ROM, leaving CGB mode with OAM index priority
+        if cgb {
+            ram.ram[0xff4c] = 0x80;
+            ram.ram[0xff6c] = 0xfe;
+            ram.ram[0xff56] = 0x3e;
+            ram.ram[0xff70] = 0xf8;
+            ram.ram[0xff75] = 0x8f;
+            // single speed, no HDMA running
+            ram.ram[0xff4d] = 0x7e;
+            ram.ram[0xff55] = 0xff;
         }
+        ram
     }
 
     #[inline]
     pub fn read(&self, i: usize) -> u8 {
-        self.ram[i]
+        match i {
+            0x8000..=0x9fff => self.read_vram(self.vram_bank, i),
+            0xc000..=0xcfff => self.wram[i - 0xc000],
+            0xd000..=0xdfff => self.wram[self.wram_bank << 12 | (i - 0xd000)],
+            0xff56 if self.cgb => {
+                // bit 1 is low while reading is enabled (bit 6-7) and light is received
+                let rp = self.ram[i];
+                let light = self.ir_light || (self.ir_loopback && rp & 1 == 1);
+                if rp & 0xc0 == 0xc0 && light { rp & !0b10 } else { rp }
+            }
+            0xff69 if self.cgb => self.bg_palette[(self.ram[0xff68] & 0x3f) as usize],
+            0xff6b if self.cgb => self.obj_palette[(self.ram[0xff6a] & 0x3f) as usize],
+            _ => self.ram[i],
+        }
+    }
+
+    #[inline]
+    pub fn read_vram(&self, bank: usize, i: usize) -> u8 {
+        self.vram[bank << 13 | (i - 0x8000)]
     }
 
     #[inline]
@@ -91,7 +151,48 @@ impl RAM {
 
     #[inline]
     pub fn write(&mut self, i: usize, v: u8) {
-        self.ram[i] = v;
+        match i {
+            0x8000..=0x9fff => self.vram[self.vram_bank << 13 | (i - 0x8000)] = v,
+            0xc000..=0xcfff => self.wram[i - 0xc000] = v,
+            0xd000..=0xdfff => self.wram[self.wram_bank << 12 | (i - 0xd000)] = v,
+            0xff4c | 0xff6c | 0xff76 | 0xff77 if self.cgb => {}
+            0xff56 if self.cgb => self.ram[i] = 0b00111110 | v & 0b11000001,
+            0xff70 if self.cgb => {
+                // bank 0 selects bank 1
+                self.wram_bank = ((v & 0b111) as usize).max(1);
+                self.ram[i] = 0xf8 | v;
+            }
+            0xff75 if self.cgb => self.ram[i] = 0x8f | v & 0x70,
+            0xff4f if self.cgb => {
+                self.vram_bank = (v & 1) as usize;
+                self.ram[i] = 0xfe | v;
+            }
+            0xff69 if self.cgb => {
+                let bcps = self.ram[0xff68];
+                self.bg_palette[(bcps & 0x3f) as usize] = v;
+                self.ram[0xff68] = RAM::increment_palette_index(bcps);
+            }
+            0xff6b if self.cgb => {
+                let ocps = self.ram[0xff6a];
+                self.obj_palette[(ocps & 0x3f) as usize] = v;
+                self.ram[0xff6a] = RAM::increment_palette_index(ocps);
+            }
+            _ => self.ram[i] = v,
+        }
+    }
+
+    // whether the infrared LED is on, for connecting to another device
+    pub fn ir_led(&self) -> bool {
+        self.cgb && self.ram[0xff56] & 1 == 1
+    }
+
+    // BCPS/OCPS bit 7 auto-increments the index after each data write
+    fn increment_palette_index(ps: u8) -> u8 {
+        if ps & 0x80 != 0 {
+            0x80 | (ps + 1) & 0x3f
+        } else {
+            ps
+        }
     }
 
     #[inline]
@@ -13,6 +13,8 @@ pub struct ROMType {
 pub enum MBCType { 
     None,
     MBC1,
+    // the cartridge type byte of a mapper that isn't emulated
+    Unsupported(u8),
 }
 
 #[derive(Debug)]
@@ -50,7 +52,7 @@ impl ROM {
                 0x03 => ROMType { mbc_type: MBCType::MBC1, ram_ex: true, battery: true, timer: false },
                 0x08 => ROMType { mbc_type: MBCType::None, ram_ex: true, battery: false, timer: false },
                 0x09 => ROMType { mbc_type: MBCType::None, ram_ex: true, battery: true, timer: false },
-                _    => ROMType { mbc_type: MBCType::None, ram_ex: false, battery: false, timer: false }
+                t    => ROMType { mbc_type: MBCType::Unsupported(t), ram_ex: false, battery: false, timer: false }
             },
             rom_size: 0x8000 * (1 << (raw[0x148] as usize)),
             ram_ex_size: match raw[0x149] {
@@ -76,6 +78,11 @@ impl ROM {
     pub fn read(&self, i: usize) -> u8 {
         self.raw[i]
     }
+
+    // the SGB only runs its functions for a cartridge with SGB flag 0x03 and old licensee 0x33
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
 }
 
 pub fn read_rom(path: String) -> Result<ROM, io::Error> {
@@ -0,0 +1,328 @@
+use crate::cpu::CPU;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::thread;
+
+pub type Serial = Box<dyn SerialDevice>;
+
+/// Something plugged into the link port. Bits are exchanged MSB first, one per serial clock.
+pub trait SerialDevice {
+    /// Called on every clock this Game Boy generates (SC bit 0 set).
+    /// `bit` is shifted out to the device, the returned bit is shifted into SB.
+    fn exchange(&mut self, bit: bool) -> bool;
+
+    /// Called every M-cycle while a transfer waits on an external clock (SC bit 0 clear).
+    /// `bit` is what this Game Boy currently drives on its output line.
+    /// Returns the incoming bit once the device has clocked one.
+    fn external(&mut self, _bit: bool) -> Option<bool> {
+        None
+    }
+
+    /// Called every M-cycle, for devices that have to keep time with this Game Boy.
+    fn tick(&mut self) {}
+}
+
+#[derive(Debug)]
+struct Wire {
+    // bits clocked towards each side, not yet shifted in
+    pending: [VecDeque<bool>; 2],
+    // output line of each side, read by the clock master
+    lines: [bool; 2],
+    // whether each side waited on an external clock in its last M-cycle, bits clocked otherwise are lost
+    ready: [bool; 2],
+}
+
+/// One end of a cable between two `CPU`s living in the same process.
+#[derive(Debug)]
+pub struct LinkPort {
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+}
+
+impl LinkPort {
+    pub fn pair() -> (LinkPort, LinkPort) {
+        let wire = Rc::new(RefCell::new(Wire {
+            pending: [VecDeque::new(), VecDeque::new()],
+            lines: [true, true],
+            ready: [false, false],
+        }));
+        (
+            LinkPort { wire: wire.clone(), side: 0 },
+            LinkPort { wire, side: 1 },
+        )
+    }
+}
+
+impl SerialDevice for LinkPort {
+    fn exchange(&mut self, bit: bool) -> bool {
+        let mut wire = self.wire.borrow_mut();
+        let other = 1 - self.side;
+        if wire.ready[other] {
+            wire.pending[other].push_back(bit);
+        }
+        wire.lines[other]
+    }
+
+    fn external(&mut self, bit: bool) -> Option<bool> {
+        let mut wire = self.wire.borrow_mut();
+        wire.ready[self.side] = true;
+        let received = wire.pending[self.side].pop_front();
+        if received.is_none() {
+            wire.lines[self.side] = bit;
+        }
+        received
+    }
+
+    fn tick(&mut self) {
+        let mut wire = self.wire.borrow_mut();
+        if !wire.ready[self.side] {
+            wire.pending[self.side].clear();
+        }
+        wire.ready[self.side] = false;
+    }
+}
+
+/// Two Game Boys connected by a link cable, run in deterministic lockstep:
+/// each step advances whichever side is behind in M-cycles.
+pub struct Link {
+    pub cpus: [CPU; 2],
+    pub cycles: [usize; 2],
+}
+
+impl Link {
+    pub fn new(mut a: CPU, mut b: CPU) -> Link {
+        let (pa, pb) = LinkPort::pair();
+        a.serial_device = Some(Box::new(pa));
+        b.serial_device = Some(Box::new(pb));
+        Link {
+            cpus: [a, b],
+            cycles: [0, 0],
+        }
+    }
+
+    pub fn step(&mut self) {
+        let i = if self.cycles[0] <= self.cycles[1] { 0 } else { 1 };
+        self.cpus[i].step();
+        self.cycles[i] += self.cpus[i].cycle;
+    }
+
+    /// Runs both sides until each has advanced at least `cycles` more M-cycles.
+    pub fn run(&mut self, cycles: usize) {
+        let target = self.cycles[0].min(self.cycles[1]) + cycles;
+        while self.cycles[0].min(self.cycles[1]) < target {
+            self.step();
+        }
+    }
+}
+
+const TCP_LINK_VERSION: u8 = 1;
+// M-cycles between sync messages, and how far the clock master may run ahead of the slave
+const TCP_LINK_SYNC_INTERVAL: u64 = 64;
+const TCP_LINK_MAX_AHEAD: u64 = 1024;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TcpLinkMessage {
+    Hello(u8, bool),
+    Sync(u64),
+    Clock(bool),
+    Reply(bool),
+}
+
+/// A link cable to another emulator process over TCP.
+/// The listening side is the timing master: the slave never runs ahead of it,
+/// and it never runs more than `TCP_LINK_MAX_AHEAD` M-cycles ahead of the slave.
+#[derive(Debug)]
+pub struct TcpLink {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    connected: bool,
+
+    pub master: bool,
+    pub cycles: u64,
+    pub remote_cycles: u64,
+
+    line: bool,
+    pending: VecDeque<bool>,
+    // whether this side waited on an external clock in its last M-cycle, clocks from the master are lost otherwise
+    ready: bool,
+    reply: Option<bool>,
+}
+
+impl TcpLink {
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        TcpLink::accept(&TcpListener::bind(addr)?)
+    }
+
+    /// Waits for the other side on a bound listener, as the master.
+    pub fn accept(listener: &TcpListener) -> io::Result<TcpLink> {
+        let (stream, _) = listener.accept()?;
+        TcpLink::handshake(stream, true)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        TcpLink::handshake(TcpStream::connect(addr)?, false)
+    }
+
+    fn handshake(stream: TcpStream, master: bool) -> io::Result<TcpLink> {
+        stream.set_nodelay(true)?;
+        let mut link = TcpLink {
+            stream,
+            buffer: vec![],
+            connected: true,
+            master,
+            cycles: 0,
+            remote_cycles: 0,
+            line: true,
+            pending: VecDeque::new(),
+            ready: false,
+            reply: None,
+        };
+
+        link.send(TcpLinkMessage::Hello(TCP_LINK_VERSION, master));
+        let mut hello = [0; 3];
+        link.stream.read_exact(&mut hello)?;
+        link.buffer.extend_from_slice(&hello);
+        match link.decode() {
+            Some(TcpLinkMessage::Hello(TCP_LINK_VERSION, remote_master)) if remote_master != master => {}
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "TcpLink: bad handshake")),
+        }
+
+        link.stream.set_nonblocking(true)?;
+        Ok(link)
+    }
+
+    fn encode(msg: TcpLinkMessage) -> Vec<u8> {
+        match msg {
+            TcpLinkMessage::Hello(version, master) => vec![b'H', version, master as u8],
+            TcpLinkMessage::Sync(cycles) => {
+                let mut v = vec![b'S'];
+                v.extend_from_slice(&cycles.to_be_bytes());
+                v
+            }
+            TcpLinkMessage::Clock(bit) => vec![b'C', bit as u8],
+            TcpLinkMessage::Reply(bit) => vec![b'R', bit as u8],
+        }
+    }
+
+    // takes the next complete message out of the buffer, an unknown message ends the connection
+    fn decode(&mut self) -> Option<TcpLinkMessage> {
+        let buf = &self.buffer;
+        let (msg, n) = match buf.first()? {
+            b'H' if buf.len() >= 3 => (TcpLinkMessage::Hello(buf[1], buf[2] != 0), 3),
+            b'S' if buf.len() >= 9 => {
+                let mut bs = [0; 8];
+                bs.copy_from_slice(&buf[1..9]);
+                (TcpLinkMessage::Sync(u64::from_be_bytes(bs)), 9)
+            }
+            b'C' if buf.len() >= 2 => (TcpLinkMessage::Clock(buf[1] != 0), 2),
+            b'R' if buf.len() >= 2 => (TcpLinkMessage::Reply(buf[1] != 0), 2),
+            b'H' | b'S' | b'C' | b'R' => return None,
+            _ => {
+                self.connected = false;
+                self.buffer.clear();
+                return None;
+            }
+        };
+        self.buffer.drain(..n);
+        Some(msg)
+    }
+
+    fn send(&mut self, msg: TcpLinkMessage) {
+        if !self.connected {
+            return;
+        }
+
+        let bytes = TcpLink::encode(msg);
+        let mut sent = 0;
+        while sent < bytes.len() {
+            match self.stream.write(&bytes[sent..]) {
+                Ok(0) => {
+                    self.connected = false;
+                    return;
+                }
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(_) => {
+                    self.connected = false;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self) {
+        let mut buf = [0; 256];
+        while self.connected {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.connected = false,
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => self.connected = false,
+            }
+        }
+
+        while let Some(msg) = self.decode() {
+            match msg {
+                TcpLinkMessage::Hello(..) => {}
+                TcpLinkMessage::Sync(cycles) => self.remote_cycles = cycles,
+                TcpLinkMessage::Clock(bit) => {
+                    if self.ready {
+                        self.pending.push_back(bit);
+                    }
+                    self.send(TcpLinkMessage::Reply(self.line));
+                }
+                TcpLinkMessage::Reply(bit) => self.reply = Some(bit),
+            }
+        }
+    }
+
+    fn blocked(&self) -> bool {
+        if self.master {
+            self.cycles > self.remote_cycles + TCP_LINK_MAX_AHEAD
+        } else {
+            self.cycles >= self.remote_cycles
+        }
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn exchange(&mut self, bit: bool) -> bool {
+        self.send(TcpLinkMessage::Clock(bit));
+        while self.connected && self.reply.is_none() {
+            self.receive();
+            thread::yield_now();
+        }
+        self.reply.take().unwrap_or(true)
+    }
+
+    fn external(&mut self, bit: bool) -> Option<bool> {
+        self.ready = true;
+        let received = self.pending.pop_front();
+        if received.is_none() {
+            self.line = bit;
+        }
+        received
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+        if self.cycles.is_multiple_of(TCP_LINK_SYNC_INTERVAL) {
+            self.send(TcpLinkMessage::Sync(self.cycles));
+            self.receive();
+            while self.connected && self.blocked() {
+                thread::yield_now();
+                self.receive();
+            }
+        }
+
+        if !self.ready {
+            self.pending.clear();
+        }
+        self.ready = false;
+    }
+}
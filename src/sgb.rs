@@ -0,0 +1,457 @@
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+// position of the Game Boy screen inside the border
+const SGB_SCREEN_X: usize = 48;
+const SGB_SCREEN_Y: usize = 40;
+
+const SGB_DEFAULT_PALETTE: [u16; 4] = [0x7fff, 0x56b5, 0x294a, 0x0000];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Transfer {
+    Chr(usize),
+    Pct,
+}
+
+/// Super Game Boy: command packets sent through JOYP, palettes, attributes and the border.
+/// `buffer` is the 256x224 RGB555 picture, updated every frame.
+pub struct SGB {
+    pub buffer: Box<[[u16; SGB_WIDTH]; SGB_HEIGHT]>,
+    // the colorized Game Boy screen, kept while MASK_EN freezes it
+    screen: Box<[[u16; 160]; 144]>,
+    // joypads of player 2-4, in the `joypad_buffer` format
+    pub joypads: [u8; 4],
+    pub players: usize,
+    pub player: usize,
+
+    joyp: u8,
+    receiving: bool,
+    ready: bool,
+    bits: usize,
+    packet: [u8; 16],
+    data: Vec<u8>,
+
+    palettes: [[u16; 4]; 4],
+    attrs: [[u8; 20]; 18],
+    mask: u8,
+    transfer: Option<Transfer>,
+    border_tiles: Vec<u8>,
+    border_map: [u16; 32 * 28],
+    border_palettes: [[u16; 16]; 4],
+}
+
+impl Default for SGB {
+    fn default() -> Self {
+        SGB::new()
+    }
+}
+
+impl SGB {
+    pub fn new() -> SGB {
+        SGB {
+            buffer: Box::new([[0; SGB_WIDTH]; SGB_HEIGHT]),
+            screen: Box::new([[0; 160]; 144]),
+            joypads: [0b11111111; 4],
+            players: 1,
+            player: 0,
+            joyp: 0b110000,
+            receiving: false,
+            ready: false,
+            bits: 0,
+            packet: [0; 16],
+            data: vec![],
+            palettes: [SGB_DEFAULT_PALETTE; 4],
+            attrs: [[0; 20]; 18],
+            mask: 0,
+            transfer: None,
+            border_tiles: vec![0; 256 * 32],
+            border_map: [0; 32 * 28],
+            border_palettes: [[0; 16]; 4],
+        }
+    }
+
+    // P14 and P15 low resets, P15 low sends a 1, P14 low sends a 0,
+    // and both high again between the bits. 128 bits and a 0 stop bit make a packet
+    pub fn write_joypad(&mut self, v: u8) {
+        let v = v & 0b110000;
+        let old = self.joyp;
+        self.joyp = v;
+
+        match v {
+            0b000000 => {
+                self.receiving = true;
+                self.ready = false;
+                self.bits = 0;
+                self.packet = [0; 16];
+            }
+            0b010000 | 0b100000 if self.receiving && self.ready => {
+                self.ready = false;
+                let bit = v == 0b010000;
+                if self.bits == 128 {
+                    self.receiving = false;
+                    if !bit {
+                        self.receive();
+                    }
+                } else {
+                    self.packet[self.bits / 8] |= (bit as u8) << (self.bits % 8);
+                    self.bits += 1;
+                }
+            }
+            0b110000 => {
+                self.ready = true;
+                // with MLT_REQ, the selected joypad advances on every rising edge of P15
+                if old & 0b100000 == 0 && self.players > 1 {
+                    self.player = (self.player + 1) % self.players;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // joypad state of the selected player
+    pub fn joypad(&self, player1: u8) -> u8 {
+        if self.player == 0 { player1 } else { self.joypads[self.player] }
+    }
+
+    // JOYP lines with neither P14 nor P15 selected: the joypad ID in multiplayer mode
+    pub fn joypad_id(&self) -> u8 {
+        if self.players > 1 { 0b1111 - self.player as u8 } else { 0b1111 }
+    }
+
+    fn receive(&mut self) {
+        if self.data.is_empty() {
+            // the first byte holds the command and the number of packets
+            if self.packet[0] & 0b111 == 0 {
+                return;
+            }
+        }
+
+        self.data.extend_from_slice(&self.packet);
+        if self.data.len() / 16 == (self.data[0] & 0b111) as usize {
+            let data = std::mem::take(&mut self.data);
+            self.command(&data);
+        }
+    }
+
+    fn command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x11 => {
+                self.players = match data[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            0x13 => self.transfer = Some(Transfer::Chr((data[1] & 1) as usize)),
+            0x14 => self.transfer = Some(Transfer::Pct),
+            0x17 => self.mask = data[1] & 0b11,
+            _ => {}
+        }
+    }
+
+    fn color(data: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes([data[i], data[i + 1]]) & 0x7fff
+    }
+
+    // PAL01, PAL23, PAL03, PAL12: color 0 is shared by all palettes
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[u8]) {
+        let color0 = SGB::color(data, 1);
+        for p in self.palettes.iter_mut() {
+            p[0] = color0;
+        }
+        for c in 1..4 {
+            self.palettes[a][c] = SGB::color(data, 1 + c * 2);
+            self.palettes[b][c] = SGB::color(data, 7 + c * 2);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min(18);
+        for set in data[2..].chunks(6).take(sets) {
+            if set.len() < 6 {
+                break;
+            }
+            let (control, palettes) = (set[0] & 0b111, set[1]);
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+            let inside = palettes & 0b11;
+            let outside = (palettes >> 4) & 0b11;
+            // with only the inside or only the outside changed, the block's edge follows it
+            let line = match control {
+                0b001 => Some(inside),
+                0b100 => Some(outside),
+                c if c & 0b010 != 0 => Some((palettes >> 2) & 0b11),
+                _ => None,
+            };
+
+            for (y, row) in self.attrs.iter_mut().enumerate() {
+                for (x, attr) in row.iter_mut().enumerate() {
+                    let in_x = x1 <= x && x <= x2;
+                    let in_y = y1 <= y && y <= y2;
+                    if in_x && in_y && (x == x1 || x == x2 || y == y1 || y == y2) {
+                        if let Some(line) = line {
+                            *attr = line;
+                        }
+                    } else if in_x && in_y {
+                        if control & 0b001 != 0 {
+                            *attr = inside;
+                        }
+                    } else if control & 0b100 != 0 {
+                        *attr = outside;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        for &set in data[2..].iter().take(data[1] as usize) {
+            let line = (set & 0b11111) as usize;
+            let palette = (set >> 5) & 0b11;
+            if set & 0x80 != 0 {
+                if let Some(row) = self.attrs.get_mut(line) {
+                    row.fill(palette);
+                }
+            } else if line < 20 {
+                for row in self.attrs.iter_mut() {
+                    row[line] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on = (data[1] >> 4) & 0b11;
+        let horizontal = data[1] & 0b1000000 != 0;
+        let line = data[2] as usize;
+
+        for (y, row) in self.attrs.iter_mut().enumerate() {
+            for (x, attr) in row.iter_mut().enumerate() {
+                let n = if horizontal { y } else { x };
+                *attr = match n.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 1 != 0;
+
+        for n in 0..count.min(360) {
+            let Some(&b) = data.get(6 + n / 4) else { break };
+            if x >= 20 || y >= 18 {
+                break;
+            }
+            self.attrs[y][x] = (b >> (6 - (n % 4) * 2)) & 0b11;
+
+            if vertical {
+                y += 1;
+                if y == 18 {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == 20 {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    // VRAM transfers read the tiles the game shows on screen, in map order
+    fn screen_data(screen: &[[u8; 160]; 144]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(0x1000);
+        for t in 0..256 {
+            let (tx, ty) = (t % 20 * 8, t / 20 * 8);
+            for y in 0..8 {
+                let (mut lo, mut hi) = (0, 0);
+                for x in 0..8 {
+                    let shade = screen[ty + y][tx + x];
+                    lo |= (shade & 1) << (7 - x);
+                    hi |= (shade >> 1 & 1) << (7 - x);
+                }
+                data.push(lo);
+                data.push(hi);
+            }
+        }
+        data
+    }
+
+    fn vram_transfer(&mut self, transfer: Transfer, screen: &[[u8; 160]; 144]) {
+        let data = SGB::screen_data(screen);
+        match transfer {
+            Transfer::Chr(bank) => {
+                self.border_tiles[bank * 0x1000..(bank + 1) * 0x1000].copy_from_slice(&data);
+            }
+            Transfer::Pct => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+                }
+                for (p, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (c, color) in palette.iter_mut().enumerate() {
+                        *color = SGB::color(&data, 0x800 + p * 32 + c * 2);
+                    }
+                }
+            }
+        }
+    }
+
+    // SNES 4bpp tile: bitplanes 0-1 in the first 16 bytes, 2-3 in the next 16
+    fn border_pixel(&self, tile: usize, x: usize, y: usize) -> usize {
+        let t = &self.border_tiles[tile * 32..tile * 32 + 32];
+        let bit = 7 - x;
+        ((t[y * 2] >> bit & 1)
+            | (t[y * 2 + 1] >> bit & 1) << 1
+            | (t[16 + y * 2] >> bit & 1) << 2
+            | (t[16 + y * 2 + 1] >> bit & 1) << 3) as usize
+    }
+
+    /// Called once per frame with the finished DMG picture.
+    pub fn frame(&mut self, screen: &[[u8; 160]; 144]) {
+        if let Some(transfer) = self.transfer.take() {
+            self.vram_transfer(transfer, screen);
+        }
+
+        // MASK_EN: 1 freezes the picture, 2 blanks it to black, 3 to color 0
+        let backdrop = self.palettes[0][0];
+        if self.mask != 1 {
+            for (y, line) in screen.iter().enumerate() {
+                for (x, &shade) in line.iter().enumerate() {
+                    self.screen[y][x] = match self.mask {
+                        2 => 0,
+                        3 => backdrop,
+                        _ => self.palettes[self.attrs[y / 8][x / 8] as usize][shade as usize],
+                    };
+                }
+            }
+        }
+
+        for row in self.buffer.iter_mut() {
+            row.fill(backdrop);
+        }
+        for (y, line) in self.screen.iter().enumerate() {
+            self.buffer[SGB_SCREEN_Y + y][SGB_SCREEN_X..SGB_SCREEN_X + 160].copy_from_slice(line);
+        }
+
+        for (i, &entry) in self.border_map.iter().enumerate() {
+            let tile = (entry & 0xff) as usize;
+            let palette = ((entry >> 10) & 0b111) as usize;
+            let (flip_x, flip_y) = (entry & 0x4000 != 0, entry & 0x8000 != 0);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let c = self.border_pixel(tile, if flip_x { 7 - x } else { x }, if flip_y { 7 - y } else { y });
+                    if c != 0 && palette >= 4 {
+                        self.buffer[i / 32 * 8 + y][i % 32 * 8 + x] = self.border_palettes[palette - 4][c];
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // reset, 128 bits with both lines high in between, then the 0 stop bit
+    fn send(sgb: &mut SGB, packet: [u8; 16]) {
+        sgb.write_joypad(0b000000);
+        sgb.write_joypad(0b110000);
+        for i in 0..128 {
+            let bit = packet[i / 8] >> (i % 8) & 1 == 1;
+            sgb.write_joypad(if bit { 0b010000 } else { 0b100000 });
+            sgb.write_joypad(0b110000);
+        }
+        sgb.write_joypad(0b100000);
+        sgb.write_joypad(0b110000);
+    }
+
+    fn packet(command: u8, data: &[u8]) -> [u8; 16] {
+        let mut packet = [0; 16];
+        packet[0] = command << 3 | 1;
+        packet[1..1 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    fn pal01() -> [u8; 16] {
+        let colors: [u16; 7] = [0x1111, 0x0001, 0x0002, 0x0003, 0x0101, 0x0102, 0x0103];
+        let data: Vec<u8> = colors.iter().flat_map(|c| c.to_le_bytes()).collect();
+        packet(0x00, &data)
+    }
+
+    // the SGB pixel of the Game Boy pixel at x, y
+    fn pixel(sgb: &SGB, x: usize, y: usize) -> u16 {
+        sgb.buffer[SGB_SCREEN_Y + y][SGB_SCREEN_X + x]
+    }
+
+    #[test]
+    fn pal01_sets_palettes_0_and_1() {
+        let mut sgb = SGB::new();
+        send(&mut sgb, pal01());
+        assert_eq!(sgb.palettes[0], [0x1111, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(sgb.palettes[1], [0x1111, 0x0101, 0x0102, 0x0103]);
+        assert_eq!(sgb.palettes[2][0], 0x1111);
+
+        sgb.frame(&[[2; 160]; 144]);
+        assert_eq!(pixel(&sgb, 0, 0), 0x0002);
+        assert_eq!(sgb.buffer[0][0], 0x1111);
+    }
+
+    #[test]
+    fn attr_blk_colors_inside_the_block() {
+        let mut sgb = SGB::new();
+        send(&mut sgb, pal01());
+        // one set: change the inside to palette 1, tiles 1-2 x 1-3
+        send(&mut sgb, packet(0x04, &[1, 0b001, 0b01, 1, 1, 2, 3]));
+        assert_eq!(sgb.attrs[0][..4], [0, 0, 0, 0]);
+        assert_eq!(sgb.attrs[1][..4], [0, 1, 1, 0]);
+        assert_eq!(sgb.attrs[3][..4], [0, 1, 1, 0]);
+        assert_eq!(sgb.attrs[4][..4], [0, 0, 0, 0]);
+
+        sgb.frame(&[[1; 160]; 144]);
+        assert_eq!(pixel(&sgb, 7, 8), 0x0001);
+        assert_eq!(pixel(&sgb, 8, 8), 0x0101);
+        assert_eq!(pixel(&sgb, 23, 31), 0x0101);
+        assert_eq!(pixel(&sgb, 24, 31), 0x0001);
+    }
+
+    #[test]
+    fn mlt_req_cycles_the_joypads() {
+        let mut sgb = SGB::new();
+        sgb.joypads[1] = 0b11101111;
+        assert_eq!(sgb.joypad_id(), 0b1111);
+
+        send(&mut sgb, packet(0x11, &[1]));
+        assert_eq!((sgb.players, sgb.player), (2, 0));
+        assert_eq!(sgb.joypad_id(), 0b1111);
+        assert_eq!(sgb.joypad(0b11111110), 0b11111110);
+
+        // P15 going high again selects the next joypad
+        sgb.write_joypad(0b010000);
+        sgb.write_joypad(0b110000);
+        assert_eq!(sgb.joypad_id(), 0b1110);
+        assert_eq!(sgb.joypad(0b11111110), 0b11101111);
+        sgb.write_joypad(0b010000);
+        sgb.write_joypad(0b110000);
+        assert_eq!(sgb.joypad_id(), 0b1111);
+
+        send(&mut sgb, packet(0x11, &[0]));
+        assert_eq!(sgb.players, 1);
+    }
+}
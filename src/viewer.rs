@@ -0,0 +1,238 @@
+use crate::ppu::PPU;
+use crate::ram::Reg;
+
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::Path;
+
+const GREYS: [[u8; 3]; 4] = [[0xff, 0xff, 0xff], [0xaa, 0xaa, 0xaa], [0x55, 0x55, 0x55], [0x00, 0x00, 0x00]];
+const VIEWPORT_COLOR: [u8; 3] = [0xff, 0x00, 0x00];
+
+/// RGBA image, 4 bytes per pixel, for debug views.
+#[derive(Debug, Clone)]
+pub struct DebugImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl DebugImage {
+    fn new(width: usize, height: usize) -> DebugImage {
+        DebugImage { width, height, pixels: vec![0; width * height * 4] }
+    }
+
+    fn set(&mut self, x: usize, y: usize, [r, g, b]: [u8; 3]) {
+        let i = (y * self.width + x) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&[r, g, b, 0xff]);
+    }
+
+    pub fn write_png(&self, path: &Path) -> io::Result<()> {
+        write_png(path, self.width, self.height, png::ColorType::Rgba, &self.pixels)
+    }
+}
+
+// 8 bits per channel, shared with the printer
+pub(crate) fn write_png(path: &Path, width: usize, height: usize, color: png::ColorType, pixels: &[u8]) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    Ok(())
+}
+
+/// One OAM entry with its attributes decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct OamEntry {
+    pub index: usize,
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub bg_priority: bool,
+    pub flip_y: bool,
+    pub flip_x: bool,
+    pub dmg_palette: usize,
+    pub bank: usize,
+    pub cgb_palette: usize,
+}
+
+/// All 384 tiles of 0x8000-0x97ff, 16 per row, with the CGB's bank 1 to the right of bank 0.
+pub fn tiles(ppu: &PPU) -> DebugImage {
+    let banks = if ppu.cgb { 2 } else { 1 };
+    let mut image = DebugImage::new(128 * banks, 192);
+    for bank in 0..banks {
+        for t in 0..384 {
+            let (tx, ty) = (bank * 128 + t % 16 * 8, t / 16 * 8);
+            for y in 0..8 {
+                for (x, &color_id) in ppu.read_tile_row(bank, 0x8000 + t as u16 * 16, y).iter().enumerate() {
+                    image.set(tx + x, ty + y, GREYS[color_id as usize]);
+                }
+            }
+        }
+    }
+    image
+}
+
+/// The 32x32 tile map at 0x9800 (`map` 0) or 0x9c00 (`map` 1), drawn with the current tile
+/// addressing mode and palettes. The background viewport is outlined on the map LCDC selects for it.
+pub fn tile_map(ppu: &PPU, map: usize) -> DebugImage {
+    let ram = ppu.mbc.get_ram();
+    let lcdc = ram.read_reg(Reg::LCDC);
+    let bgp = ram.read_reg(Reg::BGP);
+    let base = if map == 0 { 0x9800 } else { 0x9c00 };
+
+    let mut image = DebugImage::new(256, 256);
+    for i in 0..32 * 32 {
+        let tile = ram.read_vram(0, base + i);
+        let attr = if ppu.cgb { ram.read_vram(1, base + i) } else { 0 };
+        let addr = ppu.adderssing_tile(tile, false);
+        let bank = (attr >> 3 & 1) as usize;
+
+        for y in 0..8 {
+            let row = ppu.read_tile_row(bank, addr, if attr & 0x40 != 0 { 7 - y } else { y });
+            for x in 0..8 {
+                let color_id = row[if attr & 0x20 != 0 { 7 - x } else { x }];
+                let color = if ppu.cgb {
+                    PPU::rgb555(PPU::cgb_color(&ram.bg_palette, attr, color_id))
+                } else {
+                    GREYS[(bgp >> (color_id * 2) & 0b11) as usize]
+                };
+                image.set(i % 32 * 8 + x, i / 32 * 8 + y, color);
+            }
+        }
+    }
+
+    if (lcdc >> 3 & 1) as usize == map {
+        let scx = ram.read_reg(Reg::SCX) as usize;
+        let scy = ram.read_reg(Reg::SCY) as usize;
+        for x in 0..160 {
+            image.set((scx + x) % 256, scy, VIEWPORT_COLOR);
+            image.set((scx + x) % 256, (scy + 143) % 256, VIEWPORT_COLOR);
+        }
+        for y in 0..144 {
+            image.set(scx, (scy + y) % 256, VIEWPORT_COLOR);
+            image.set((scx + 159) % 256, (scy + y) % 256, VIEWPORT_COLOR);
+        }
+    }
+    image
+}
+
+pub fn oam_entries(ppu: &PPU) -> Vec<OamEntry> {
+    let ram = ppu.mbc.get_ram();
+    (0..40)
+        .map(|index| {
+            let o = 0xfe00 + index * 4;
+            let attr = ram.read(o + 3);
+            OamEntry {
+                index,
+                y: ram.read(o),
+                x: ram.read(o + 1),
+                tile: ram.read(o + 2),
+                bg_priority: attr & 0x80 != 0,
+                flip_y: attr & 0x40 != 0,
+                flip_x: attr & 0x20 != 0,
+                dmg_palette: (attr >> 4 & 1) as usize,
+                bank: if ppu.cgb { (attr >> 3 & 1) as usize } else { 0 },
+                cgb_palette: (attr & 0b111) as usize,
+            }
+        })
+        .collect()
+}
+
+/// The 40 objects in OAM order, 8 per row, each in a 8x16 cell with a 1 pixel gap.
+/// Transparent pixels are left with alpha 0.
+pub fn oam(ppu: &PPU) -> DebugImage {
+    let ram = ppu.mbc.get_ram();
+    let tall = ram.read_reg(Reg::LCDC) & 0b100 != 0;
+    let obj_len = if tall { 16 } else { 8 };
+
+    let mut image = DebugImage::new(8 * 9 + 1, 5 * 17 + 1);
+    for e in oam_entries(ppu) {
+        let (cx, cy) = (1 + e.index % 8 * 9, 1 + e.index / 8 * 17);
+        let obp = ram.read_reg(if e.dmg_palette == 1 { Reg::OBP1 } else { Reg::OBP0 });
+        let tile = if tall { e.tile & 0xfe } else { e.tile };
+
+        for y in 0..obj_len {
+            let row = if e.flip_y { obj_len - 1 - y } else { y };
+            let pixels = ppu.read_tile_row(e.bank, ppu.adderssing_tile(tile + row as u8 / 8, true), row % 8);
+            for x in 0..8 {
+                let color_id = pixels[if e.flip_x { 7 - x } else { x }];
+                if color_id == 0 {
+                    continue;
+                }
+                let color = if ppu.cgb {
+                    PPU::rgb555(PPU::cgb_color(&ram.obj_palette, e.cgb_palette as u8, color_id))
+                } else {
+                    GREYS[(obp >> (color_id * 2) & 0b11) as usize]
+                };
+                image.set(cx + x, cy + y, color);
+            }
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::select_mbc;
+    use crate::rom::ROM;
+
+    // tile 1 all color 1, object 0 showing it with BG priority, flipped X and OBP1
+    fn ppu() -> PPU {
+        let mut ppu = PPU::new(select_mbc(ROM::new(vec![0; 0x8000])));
+        let ram = ppu.mbc.get_ram_mut();
+        for i in 0..8 {
+            ram.write(0x8010 + i * 2, 0xff);
+        }
+        ram.write(0x9800, 0x01);
+        for (i, v) in [16, 8, 0x01, 0xb0].into_iter().enumerate() {
+            ram.write(0xfe00 + i, v);
+        }
+        ram.write(0xff40, 0x91);
+        ram.write(0xff47, 0xe4);
+        ram.write(0xff49, 0x1b);
+        ppu
+    }
+
+    fn pixel(image: &DebugImage, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * image.width + x) * 4;
+        image.pixels[i..i + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn oam_entry_attributes() {
+        let e = oam_entries(&ppu())[0];
+        assert_eq!((e.index, e.y, e.x, e.tile), (0, 16, 8, 1));
+        assert!(e.bg_priority && e.flip_x && !e.flip_y);
+        assert_eq!((e.dmg_palette, e.bank, e.cgb_palette), (1, 0, 0));
+    }
+
+    #[test]
+    fn tiles_in_vram_order() {
+        let image = tiles(&ppu());
+        assert_eq!((image.width, image.height), (128, 192));
+        assert_eq!(pixel(&image, 7, 7), [0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(pixel(&image, 8, 0), [0xaa, 0xaa, 0xaa, 0xff]);
+        assert_eq!(pixel(&image, 15, 7), [0xaa, 0xaa, 0xaa, 0xff]);
+    }
+
+    #[test]
+    fn tile_map_with_bgp() {
+        let image = tile_map(&ppu(), 0);
+        assert_eq!(pixel(&image, 1, 1), [0xaa, 0xaa, 0xaa, 0xff]);
+        assert_eq!(pixel(&image, 9, 1), [0xff, 0xff, 0xff, 0xff]);
+        // the viewport outline
+        assert_eq!(pixel(&image, 0, 0), [0xff, 0x00, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn oam_with_obp1() {
+        let image = oam(&ppu());
+        assert_eq!(pixel(&image, 1, 1), [0x55, 0x55, 0x55, 0xff]);
+        assert_eq!(pixel(&image, 1, 9), [0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(pixel(&image, 10, 1), [0x00, 0x00, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,135 @@
+// Headless runs of the test ROMs in rom/, see the README for where they come from.
+// A missing ROM fails the test.
+#![allow(dead_code)]
+
+use gbe_rs::cpu::CPU;
+use gbe_rs::mbc::select_mbc;
+use gbe_rs::ppu::PPU;
+use gbe_rs::rom::{read_rom, ROM};
+use gbe_rs::serial::SerialDevice;
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// M-cycles per second at normal speed
+pub const SECOND: usize = 1048576;
+
+pub fn rom_path(rom: &str) -> PathBuf {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("rom").join(rom);
+    assert!(path.exists(), "{} not found, see the test ROMs section of the README", path.display());
+    path
+}
+
+pub fn load(rom: &str) -> CPU {
+    let rom = read_rom(rom_path(rom).to_string_lossy().into_owned()).unwrap();
+    let mut cpu = CPU::new(PPU::new(select_mbc(rom)));
+    cpu.cpu_logger.logging = false;
+    cpu
+}
+
+/// A 32 KiB ROM-only cartridge running `code` from 0x150.
+pub fn program(code: &[u8]) -> CPU {
+    program_at(&[(0x150, code)])
+}
+
+/// A 32 KiB ROM-only cartridge with each piece of code at its address, starting at 0x150.
+pub fn program_at(parts: &[(usize, &[u8])]) -> CPU {
+    let mut raw = vec![0; 0x8000];
+    raw[0x100..0x103].copy_from_slice(&[0xc3, 0x50, 0x01]);
+    for (addr, code) in parts {
+        raw[*addr..*addr + code.len()].copy_from_slice(code);
+    }
+    let mut cpu = CPU::new(PPU::new(select_mbc(ROM::new(raw))));
+    cpu.cpu_logger.logging = false;
+    cpu
+}
+
+// collects the bytes a ROM sends with its internal clock, and answers with 1s as if no cable was plugged in
+struct Capture {
+    bytes: Rc<RefCell<Vec<u8>>>,
+    byte: u8,
+    bits: usize,
+}
+
+impl SerialDevice for Capture {
+    fn exchange(&mut self, bit: bool) -> bool {
+        self.byte = self.byte << 1 | bit as u8;
+        self.bits += 1;
+        if self.bits == 8 {
+            self.bytes.borrow_mut().push(self.byte);
+            self.bits = 0;
+        }
+        true
+    }
+}
+
+fn capture(cpu: &mut CPU) -> Rc<RefCell<Vec<u8>>> {
+    let bytes = Rc::new(RefCell::new(vec![]));
+    cpu.serial_device = Some(Box::new(Capture { bytes: bytes.clone(), byte: 0, bits: 0 }));
+    bytes
+}
+
+/// Runs `cpu` for at most `cycles` M-cycles, until `done` accepts the serial output so far.
+pub fn run_serial(cpu: &mut CPU, cycles: usize, done: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    let bytes = capture(cpu);
+    let mut n = 0;
+    while n < cycles && !done(&bytes.borrow()) {
+        cpu.step();
+        n += cpu.cycle;
+    }
+    let out = bytes.borrow().clone();
+    out
+}
+
+/// Mooneye test suite: the Fibonacci numbers are sent on success, 0x42 six times on failure.
+pub fn mooneye(rom: &str) {
+    let mut cpu = load(rom);
+    let out = run_serial(&mut cpu, 20 * SECOND, |out| out.len() >= 6);
+    assert_eq!(out, [3, 5, 8, 13, 21, 34], "{} failed", rom);
+}
+
+// blargg's tests also keep their result in cartridge RAM: 0x80 while running, then 0 on success,
+// behind the 0xde 0xb0 0x61 signature and followed by the text
+fn blargg_ram(cpu: &CPU) -> Option<(u8, String)> {
+    let mbc = &cpu.ppu.mbc;
+    if mbc.get_rom().ram_ex_size == 0 || (1..4).map(|i| mbc.read(0xa000 + i)).ne([0xde, 0xb0, 0x61]) {
+        return None;
+    }
+    let status = mbc.read(0xa000);
+    if status == 0x80 {
+        return None;
+    }
+    let text = (0xa004..0xc000).map(|i| mbc.read(i)).take_while(|&c| c != 0).map(|c| c as char).collect();
+    Some((status, text))
+}
+
+/// blargg's tests: "Passed" or "Failed" on the serial port, or the result in cartridge RAM.
+pub fn blargg(rom: &str, seconds: usize) {
+    let mut cpu = load(rom);
+    let finished = |out: &[u8]| {
+        let text = String::from_utf8_lossy(out);
+        text.contains("Passed") || text.contains("Failed")
+    };
+
+    let bytes = capture(&mut cpu);
+    let mut n = 0;
+    let mut ram = None;
+    while n < seconds * SECOND && !finished(&bytes.borrow()) {
+        cpu.step();
+        n += cpu.cycle;
+        // checking RAM is slow, do it once a frame
+        if cpu.ppu.vblank_entered {
+            ram = blargg_ram(&cpu);
+            if ram.is_some() {
+                break;
+            }
+        }
+    }
+
+    let text = String::from_utf8_lossy(&bytes.borrow()).into_owned();
+    match ram {
+        Some((status, ram_text)) => assert_eq!(status, 0, "{} failed:\n{}", rom, ram_text),
+        None => assert!(text.contains("Passed"), "{} did not pass:\n{}", rom, text),
+    }
+}
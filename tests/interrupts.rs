@@ -0,0 +1,71 @@
+// mooneye's interrupt dispatch tests, and checks of the dispatch that run without the test ROMs
+mod common;
+
+use common::{mooneye, program_at};
+use gbe_rs::cpu::CPU;
+
+#[test]
+fn mooneye_ie_push() {
+    mooneye("mooneye-test-suite/acceptance/interrupts/ie_push.gb");
+}
+
+#[test]
+fn mooneye_intr_timing() {
+    mooneye("mooneye-test-suite/acceptance/intr_timing.gb");
+}
+
+const LOOP: [u8; 2] = [0x18, 0xfe];
+
+// LD A,v; LD (0xc000),A; JR -2
+fn mark(v: u8) -> Vec<u8> {
+    [&[0x3e, v, 0xea, 0x00, 0xc0][..], &LOOP].concat()
+}
+
+// requests the timer interrupt with IME set, SP = sp
+fn request_timer(sp: u16) -> Vec<u8> {
+    let [lo, hi] = sp.to_le_bytes();
+    vec![
+        0x31, lo, hi, // LD SP,sp
+        0x3e, 0x04, 0xe0, 0xff, // IE = timer
+        0xe0, 0x0f, // IF = timer
+        0xfb, 0x00, // EI; NOP
+    ]
+}
+
+fn run(cpu: &mut CPU, cycles: usize) {
+    let mut n = 0;
+    while n < cycles {
+        cpu.step();
+        n += cpu.cycle;
+    }
+}
+
+#[test]
+fn interrupt_dispatch_takes_5_m_cycles() {
+    let mut cpu = program_at(&[(0x50, &LOOP), (0x150, &[request_timer(0xdff0), LOOP.to_vec()].concat())]);
+    while cpu.pc != 0x50 {
+        cpu.step();
+    }
+    // the NOP after EI, then the dispatch
+    assert_eq!(cpu.cycle, 1 + 5);
+    assert_eq!(cpu.sp, 0xdfee);
+    assert_eq!(cpu.ppu.mbc.read(0xff0f) & 0x04, 0);
+}
+
+#[test]
+fn interrupt_dispatch_jumps_to_the_vector() {
+    let mut cpu = program_at(&[(0x00, &mark(0x11)), (0x50, &mark(0x22)), (0x150, &[request_timer(0xdff0), LOOP.to_vec()].concat())]);
+    run(&mut cpu, 1000);
+    assert_eq!(cpu.ppu.mbc.read(0xc000), 0x22);
+}
+
+#[test]
+fn ie_push_cancels_the_dispatch() {
+    // with SP = 0, the high byte of PC, 0x01, is pushed to IE and disables the timer interrupt,
+    // so the dispatch jumps to 0x0000 instead of the vector
+    let mut cpu = program_at(&[(0x00, &mark(0x11)), (0x50, &mark(0x22)), (0x150, &[request_timer(0x0000), LOOP.to_vec()].concat())]);
+    run(&mut cpu, 1000);
+    assert_eq!(cpu.ppu.mbc.read(0xc000), 0x11);
+    assert_eq!(cpu.ppu.mbc.read(0xffff), 0x01);
+    assert_eq!(cpu.ppu.mbc.read(0xff0f) & 0x04, 0x04);
+}
@@ -0,0 +1,118 @@
+// Two CPUs exchanging bytes through the in-process link cable, and the serial clock
+mod common;
+
+use common::program;
+use gbe_rs::cpu::CPU;
+use gbe_rs::serial::{Link, TcpLink};
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+// SB = v, start a transfer with SC = sc, wait for it and store SB at addr
+fn transfer(v: u8, sc: u8, addr: u16) -> Vec<u8> {
+    let [lo, hi] = addr.to_le_bytes();
+    vec![
+        0x3e, v, 0xe0, 0x01, // LD A,v; LDH (SB),A
+        0x3e, sc, 0xe0, 0x02, // LD A,sc; LDH (SC),A
+        0xf0, 0x02, 0xcb, 0x7f, 0x20, 0xfa, // LDH A,(SC); BIT 7,A; JR NZ,-6
+        0xf0, 0x01, 0xea, lo, hi, // LDH A,(SB); LD (addr),A
+    ]
+}
+
+// about 7 M-cycles per loop
+fn delay(n: u16) -> Vec<u8> {
+    let [lo, hi] = n.to_le_bytes();
+    vec![0x01, lo, hi, 0x0b, 0x78, 0xb1, 0x20, 0xfb]
+}
+
+// JR -2
+const LOOP: [u8; 2] = [0x18, 0xfe];
+
+#[test]
+fn link_transfers_a_byte_both_ways() {
+    let master = program(&[transfer(0x5a, 0x81, 0xc000), LOOP.to_vec()].concat());
+    let slave = program(&[transfer(0xa5, 0x80, 0xc000), LOOP.to_vec()].concat());
+    let mut link = Link::new(master, slave);
+    link.run(5000);
+
+    assert_eq!(link.cpus[0].ppu.mbc.read(0xc000), 0xa5);
+    assert_eq!(link.cpus[1].ppu.mbc.read(0xc000), 0x5a);
+}
+
+#[test]
+fn link_drops_bits_the_slave_is_not_waiting_for() {
+    // the first byte is clocked out before the slave starts its transfer
+    let master = program(&[transfer(0x11, 0x81, 0xc000), delay(0x400), transfer(0x22, 0x81, 0xc001), LOOP.to_vec()].concat());
+    let slave = program(&[delay(0x200), transfer(0x99, 0x80, 0xc000), LOOP.to_vec()].concat());
+    let mut link = Link::new(master, slave);
+    link.run(20000);
+
+    assert_eq!(link.cpus[0].ppu.mbc.read(0xc000), 0xff);
+    assert_eq!(link.cpus[0].ppu.mbc.read(0xc001), 0x99);
+    assert_eq!(link.cpus[1].ppu.mbc.read(0xc000), 0x22);
+}
+
+fn run(cpu: &mut CPU, cycles: usize) {
+    let mut n = 0;
+    while n < cycles {
+        cpu.step();
+        n += cpu.cycle;
+    }
+}
+
+#[test]
+fn tcp_link_transfers_a_byte_both_ways() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let master = thread::spawn(move || {
+        let mut cpu = program(&[transfer(0x5a, 0x81, 0xc000), LOOP.to_vec()].concat());
+        cpu.serial_device = Some(Box::new(TcpLink::accept(&listener).unwrap()));
+        run(&mut cpu, 20000);
+        cpu.ppu.mbc.read(0xc000)
+    });
+    let slave = thread::spawn(move || {
+        let mut cpu = program(&[transfer(0xa5, 0x80, 0xc000), LOOP.to_vec()].concat());
+        cpu.serial_device = Some(Box::new(TcpLink::connect(addr).unwrap()));
+        run(&mut cpu, 20000);
+        cpu.ppu.mbc.read(0xc000)
+    });
+
+    assert_eq!(master.join().unwrap(), 0xa5);
+    assert_eq!(slave.join().unwrap(), 0x5a);
+}
+
+#[test]
+fn tcp_link_disconnects_on_an_unknown_message() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let peer = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut hello = [0; 3];
+        stream.read_exact(&mut hello).unwrap();
+        stream.write_all(&[b'H', hello[1], 0, 0xff, 0xff]).unwrap();
+        // keep the connection open until the master is done
+        let _ = stream.read(&mut [0; 256]);
+    });
+
+    let mut cpu = program(&[transfer(0x5a, 0x81, 0xc000), LOOP.to_vec()].concat());
+    cpu.serial_device = Some(Box::new(TcpLink::accept(&listener).unwrap()));
+    run(&mut cpu, 20000);
+    assert_eq!(cpu.ppu.mbc.read(0xc000), 0xff);
+    drop(cpu);
+    peer.join().unwrap();
+}
+
+#[test]
+fn dmg_has_no_fast_serial_clock() {
+    // SC = 0x83, read it back to 0xc000
+    let mut cpu = program(&[&[0x3e, 0x83, 0xe0, 0x02, 0xf0, 0x02, 0xea, 0x00, 0xc0][..], &LOOP].concat());
+    // 8 bits take 32 M-cycles with the fast clock, 1024 without
+    run(&mut cpu, 200);
+    assert_eq!(cpu.ppu.mbc.read(0xc000), 0xff);
+    assert_eq!(cpu.ppu.mbc.read(0xff02), 0xff);
+    run(&mut cpu, 1000);
+    assert_eq!(cpu.ppu.mbc.read(0xff02), 0x7f);
+}
@@ -0,0 +1,193 @@
+// Runs PPU test ROMs, or programs built here, headlessly and compares the last frame with a reference PNG.
+// ROMs are read from rom/ and references from tests/screenshots/, a test fails when either is missing.
+
+mod common;
+
+use gbe_rs::cpu::CPU;
+use gbe_rs::framebuffer::FrameBuffer;
+use gbe_rs::viewer::DebugImage;
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+// a frame is 17556 M-cycles and every step takes at least one,
+// the rest is room for frames the LCD is switched off in
+const MAX_STEPS_PER_FRAME: usize = 70224;
+
+fn run(mut cpu: CPU, frames: usize) -> FrameBuffer {
+    let mut n = 0;
+    for _ in 0..frames * MAX_STEPS_PER_FRAME {
+        cpu.step();
+        if cpu.ppu.vblank_entered {
+            n += 1;
+            if n == frames {
+                break;
+            }
+        }
+    }
+
+    let mut frame = FrameBuffer::new();
+    frame.update(&cpu);
+    frame
+}
+
+fn read_png(path: &Path) -> DebugImage {
+    let mut decoder = png::Decoder::new(File::open(path).unwrap());
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).unwrap();
+
+    let pixels = buf[..info.buffer_size()]
+        .chunks(info.color_type.samples())
+        .flat_map(|p| match info.color_type {
+            png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => [p[0], p[0], p[0], 0xff],
+            _ => [p[0], p[1], p[2], 0xff],
+        })
+        .collect();
+    DebugImage { width: info.width as usize, height: info.height as usize, pixels }
+}
+
+fn to_image(frame: &FrameBuffer) -> DebugImage {
+    let pixels = frame.pixels.iter().flat_map(|&p| [(p >> 16) as u8, (p >> 8) as u8, p as u8, 0xff]).collect();
+    DebugImage { width: frame.width, height: frame.height, pixels }
+}
+
+// differing pixels in red over a faded copy of the reference
+fn diff(actual: &DebugImage, reference: &DebugImage) -> (DebugImage, usize) {
+    let mut count = 0;
+    let pixels = actual
+        .pixels
+        .chunks(4)
+        .zip(reference.pixels.chunks(4))
+        .flat_map(|(a, r)| {
+            if a == r {
+                [r[0] / 4 + 0xc0, r[1] / 4 + 0xc0, r[2] / 4 + 0xc0, 0xff]
+            } else {
+                count += 1;
+                [0xff, 0x00, 0x00, 0xff]
+            }
+        })
+        .collect();
+    (DebugImage { width: actual.width, height: actual.height, pixels }, count)
+}
+
+fn check(name: &str, rom: &str, frames: usize) {
+    check_cpu(name, common::load(rom), frames);
+}
+
+fn check_cpu(name: &str, cpu: CPU, frames: usize) {
+    let reference = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("screenshots").join(format!("{}.png", name));
+    let out = |suffix: &str| -> PathBuf { Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}-{}.png", name, suffix)) };
+
+    let actual = to_image(&run(cpu, frames));
+    actual.write_png(&out("actual")).unwrap();
+    assert!(
+        reference.exists(),
+        "{}: {} not found, see the screenshot tests in the README. The screenshot is in {}",
+        name,
+        reference.display(),
+        out("actual").display()
+    );
+
+    let reference = read_png(&reference);
+    assert_eq!((actual.width, actual.height), (reference.width, reference.height), "{}: size differs", name);
+    let (image, count) = diff(&actual, &reference);
+    if count > 0 {
+        image.write_png(&out("diff")).unwrap();
+        panic!("{}: {} pixels differ, see {}", name, count, out("diff").display());
+    }
+}
+
+// LD A,v; LD (addr),A
+fn store(addr: u16, v: u8) -> Vec<u8> {
+    let [lo, hi] = addr.to_le_bytes();
+    vec![0x3e, v, 0xea, lo, hi]
+}
+
+// tiles 1-3 in color 1-3, a checkerboard of tiles 1 and 2 in the background, the window of tile 3
+// in the bottom right quarter from WX 87 and WY 72, and object 0 of tile 3 at 16,16 with an all white OBP0
+#[test]
+fn bg_window_and_object() {
+    let mut code = vec![];
+    for (t, (lo, hi)) in [(0xff, 0x00), (0x00, 0xff), (0xff, 0xff)].into_iter().enumerate() {
+        for row in 0..8 {
+            code.extend(store(0x8010 + t as u16 * 16 + row * 2, lo));
+            code.extend(store(0x8010 + t as u16 * 16 + row * 2 + 1, hi));
+        }
+    }
+    for y in 0..18 {
+        for x in 0..20 {
+            code.extend(store(0x9800 + y * 32 + x, if (x + y) % 2 == 0 { 1 } else { 2 }));
+        }
+    }
+    for y in 0..9 {
+        for x in 0..10 {
+            code.extend(store(0x9c00 + y * 32 + x, 3));
+        }
+    }
+    for (i, v) in [32, 24, 3, 0].into_iter().enumerate() {
+        code.extend(store(0xfe00 + i as u16, v));
+    }
+    for (reg, v) in [(0xff47, 0xe4), (0xff48, 0x00), (0xff4a, 72), (0xff4b, 87), (0xff40, 0xf3)] {
+        code.extend(store(reg, v));
+    }
+    code.extend([0x18, 0xfe]);
+
+    check_cpu("bg-window-object", common::program(&code), 5);
+}
+
+#[test]
+fn dmg_acid2() {
+    check("dmg-acid2", "dmg-acid2.gb", 30);
+}
+
+#[test]
+fn cgb_acid2() {
+    check("cgb-acid2", "cgb-acid2.gbc", 30);
+}
+
+// mealybug-tearoom-tests, register writes during mode 3. The references are the DMG-blob ones
+fn mealybug(name: &str) {
+    check(name, &format!("mealybug-tearoom-tests/ppu/{}.gb", name), 30);
+}
+
+#[test]
+fn mealybug_m3_bgp_change() {
+    mealybug("m3_bgp_change");
+}
+
+#[test]
+fn mealybug_m3_bgp_change_sprites() {
+    mealybug("m3_bgp_change_sprites");
+}
+
+#[test]
+fn mealybug_m3_obp0_change() {
+    mealybug("m3_obp0_change");
+}
+
+#[test]
+fn mealybug_m3_lcdc_obj_en_change() {
+    mealybug("m3_lcdc_obj_en_change");
+}
+
+#[test]
+fn mealybug_m3_lcdc_obj_size_change() {
+    mealybug("m3_lcdc_obj_size_change");
+}
+
+#[test]
+fn mealybug_m3_scx_low_3_bits() {
+    mealybug("m3_scx_low_3_bits");
+}
+
+#[test]
+fn mealybug_m3_window_timing() {
+    mealybug("m3_window_timing");
+}
+
+#[test]
+fn mealybug_m3_wx_4_change() {
+    mealybug("m3_wx_4_change");
+}
@@ -0,0 +1,24 @@
+// blargg's dmg_sound tests, one ROM per APU behavior
+mod common;
+
+use common::blargg;
+
+#[test]
+fn blargg_dmg_sound() {
+    for rom in [
+        "01-registers",
+        "02-len ctr",
+        "03-trigger",
+        "04-sweep",
+        "05-sweep details",
+        "06-overflow on trigger",
+        "07-len sweep period sync",
+        "08-len ctr during power",
+        "09-wave read while on",
+        "10-wave trigger while on",
+        "11-regs after power",
+        "12-wave write while on",
+    ] {
+        blargg(&format!("gb-test-roms/dmg_sound/rom_singles/{}.gb", rom), 20);
+    }
+}
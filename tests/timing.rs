@@ -0,0 +1,83 @@
+// mooneye's timer suite and blargg's instruction and memory access timing tests,
+// and a few timer checks that run without the test ROMs
+mod common;
+
+use common::{blargg, mooneye, program};
+use gbe_rs::cpu::CPU;
+
+#[test]
+fn mooneye_timer() {
+    for rom in [
+        "div_write",
+        "rapid_toggle",
+        "tim00",
+        "tim00_div_trigger",
+        "tim01",
+        "tim01_div_trigger",
+        "tim10",
+        "tim10_div_trigger",
+        "tim11",
+        "tim11_div_trigger",
+        "tima_reload",
+        "tima_write_reloading",
+        "tma_write_reloading",
+    ] {
+        mooneye(&format!("mooneye-test-suite/acceptance/timer/{}.gb", rom));
+    }
+}
+
+#[test]
+fn blargg_instr_timing() {
+    blargg("gb-test-roms/instr_timing/instr_timing.gb", 10);
+}
+
+#[test]
+fn blargg_mem_timing() {
+    blargg("gb-test-roms/mem_timing/mem_timing.gb", 10);
+}
+
+fn run(code: &[u8], cycles: usize) -> CPU {
+    let mut cpu = program(&[code, &LOOP].concat());
+    let mut n = 0;
+    while n < cycles {
+        cpu.step();
+        n += cpu.cycle;
+    }
+    cpu
+}
+
+const LOOP: [u8; 2] = [0x18, 0xfe];
+
+#[test]
+fn tima_counts_at_the_tac_rate() {
+    // TAC and the TIMA period in M-cycles
+    for (tac, period) in [(0x04, 256), (0x05, 4), (0x06, 16), (0x07, 64)] {
+        // two TIMA reads a whole number of periods apart, 7 M-cycles are spent outside the NOPs
+        let window: usize = 256;
+        let code = [
+            &[0x3e, 0x00, 0xe0, 0x05, 0x3e, tac, 0xe0, 0x07][..], // TIMA = 0, TAC = tac
+            &[0xf0, 0x05, 0xea, 0x00, 0xc0], // LDH A,(TIMA); LD (0xc000),A
+            &vec![0x00; window - 7],
+            &[0xf0, 0x05, 0xea, 0x01, 0xc0], // LDH A,(TIMA); LD (0xc001),A
+        ]
+        .concat();
+        let cpu = run(&code, 1000);
+        let (first, second) = (cpu.ppu.mbc.read(0xc000), cpu.ppu.mbc.read(0xc001));
+        assert_eq!(second.wrapping_sub(first) as usize, window / period, "TAC {:02x}", tac);
+    }
+}
+
+#[test]
+fn div_write_clocks_tima_on_a_falling_edge() {
+    // the first of the 16 DIV writes comes 6 M-cycles after the reset, bit 3 of the counter falls once by itself
+    // and is high again when it is reset. After that DIV is written every 3 M-cycles, bit 3 never falls
+    // by itself but is high at every reset, so each write increments TIMA
+    let code = [
+        &[0x3e, 0x05, 0xe0, 0x07, 0xaf, 0xe0, 0x04, 0xe0, 0x05][..], // TAC = 0x05; XOR A; DIV = 0; TIMA = 0
+        &[0xe0, 0x04].repeat(16), // LDH (DIV),A
+        &[0xf0, 0x05, 0xea, 0x00, 0xc0], // LDH A,(TIMA); LD (0xc000),A
+    ]
+    .concat();
+    let cpu = run(&code, 1000);
+    assert_eq!(cpu.ppu.mbc.read(0xc000), 17);
+}